@@ -0,0 +1,382 @@
+//!
+//! HTTP API used by the operators, the websocket protocol is handled in client_ws.
+//! Every admin route requires the "Authorization: Bearer <ADMIN_TOKEN>" header.
+//!
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use actix::Addr;
+use actix_web::{error, Error, HttpRequest, HttpResponse, web};
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::locale::Locale;
+use crate::protocol::{self, InviteId, SerId};
+use crate::server_actor::{self, ServerActor};
+
+/// Max length of the announcements' text (in characters).
+const MAX_ANNOUNCEMENT_LEN: usize = 500;
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.route("/health", web::get().to(health));
+    cfg.route("/api/health", web::get().to(health));
+    cfg.route("/capacity", web::get().to(capacity));
+    cfg.route("/session/status", web::post().to(session_status));
+    cfg.route("/api/metrics", web::get().to(metrics));
+    cfg.service(
+        web::scope("/api/admin")
+            .route("/players", web::get().to(list_players))
+            .route("/room", web::get().to(dump_room))
+            .route("/games", web::get().to(list_completed_games))
+            .route("/room", web::post().to(create_persistent_room))
+            .route("/announcement", web::post().to(broadcast_announcement))
+    );
+}
+
+/// Liveness probe, fails as soon as the shutdown drain starts and once the ServerActor is stopped.
+async fn health(db: web::Data<Addr<ServerActor>>) -> HttpResponse {
+    match db.send(server_actor::IsShuttingDown).await {
+        Ok(false) => HttpResponse::Ok().json(HealthResponse { status: "ok" }),
+        Ok(true) => HttpResponse::ServiceUnavailable().json(HealthResponse { status: "draining" }),
+        Err(_) => HttpResponse::ServiceUnavailable().json(HealthResponse { status: "unavailable" }),
+    }
+}
+
+/// Lets the clients know if they can create rooms before connecting.
+async fn capacity(db: web::Data<Addr<ServerActor>>) -> Result<HttpResponse, Error> {
+    let res = db.send(server_actor::GetCapacity)
+        .await.map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(res))
+}
+
+#[derive(Deserialize)]
+pub struct SessionStatusBody {
+    token: String,
+}
+
+/// Lets a client with a stored reconnect token know if it can still resume its session, without taking it.
+async fn session_status(
+    body: web::Json<SessionStatusBody>,
+    db: web::Data<Addr<ServerActor>>,
+) -> Result<HttpResponse, Error> {
+    if !protocol::is_valid_reconnect_token(&body.token) {
+        return Ok(HttpResponse::BadRequest().finish());
+    }
+    let res = db.send(server_actor::GetSessionStatus {
+        token: body.into_inner().token,
+    }).await.map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(res))
+}
+
+/// Counters for the monitoring, cheap enough to be scraped often.
+async fn metrics(db: web::Data<Addr<ServerActor>>) -> Result<HttpResponse, Error> {
+    let res = db.send(server_actor::GetStats)
+        .await.map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(res))
+}
+
+fn is_admin(req: &HttpRequest, config: &Config) -> bool {
+    if config.admin_token.is_empty() {
+        return false;
+    }
+    let header = match req.headers().get("Authorization").and_then(|x| x.to_str().ok()) {
+        Some(x) => x,
+        None => return false,
+    };
+    header.strip_prefix("Bearer ") == Some(config.admin_token.as_str())
+}
+
+#[derive(Serialize)]
+struct HealthResponse {
+    status: &'static str,
+}
+
+#[derive(Deserialize)]
+pub struct ListPlayersQuery {
+    room: Option<SerId>,
+    page: Option<usize>,
+}
+
+async fn list_players(
+    req: HttpRequest,
+    query: web::Query<ListPlayersQuery>,
+    db: web::Data<Addr<ServerActor>>,
+    config: web::Data<Arc<Config>>,
+) -> Result<HttpResponse, Error> {
+    if !is_admin(&req, &config) {
+        return Ok(HttpResponse::Unauthorized().finish());
+    }
+
+    let res = db.send(server_actor::ListPlayers {
+        room: query.room.map(|x| x.into()),
+        page: query.page.unwrap_or(0),
+    }).await.map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(res))
+}
+
+#[derive(Deserialize)]
+pub struct DumpRoomQuery {
+    id: SerId,
+}
+
+async fn dump_room(
+    req: HttpRequest,
+    query: web::Query<DumpRoomQuery>,
+    db: web::Data<Addr<ServerActor>>,
+    config: web::Data<Arc<Config>>,
+) -> Result<HttpResponse, Error> {
+    if !is_admin(&req, &config) {
+        return Ok(HttpResponse::Unauthorized().finish());
+    }
+
+    let res = db.send(server_actor::DumpRoom {
+        room_id: query.id.into(),
+    }).await.map_err(error::ErrorInternalServerError)?;
+
+    Ok(match res {
+        Some(x) => HttpResponse::Ok().json(x),
+        None => HttpResponse::NotFound().finish(),
+    })
+}
+
+async fn list_completed_games(
+    req: HttpRequest,
+    db: web::Data<Addr<ServerActor>>,
+    config: web::Data<Arc<Config>>,
+) -> Result<HttpResponse, Error> {
+    if !is_admin(&req, &config) {
+        return Ok(HttpResponse::Unauthorized().finish());
+    }
+
+    let res = db.send(server_actor::ListCompletedGames)
+        .await.map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(res))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PersistentRoomBody {
+    invite_id: InviteId,
+}
+
+async fn create_persistent_room(
+    req: HttpRequest,
+    body: web::Json<PersistentRoomBody>,
+    db: web::Data<Addr<ServerActor>>,
+    config: web::Data<Arc<Config>>,
+) -> Result<HttpResponse, Error> {
+    if !is_admin(&req, &config) {
+        return Ok(HttpResponse::Unauthorized().finish());
+    }
+
+    let created = db.send(server_actor::CreatePersistentRoom {
+        room_id: body.invite_id.id,
+    }).await.map_err(error::ErrorInternalServerError)?;
+
+    Ok(if created {
+        HttpResponse::Created().finish()
+    } else {
+        HttpResponse::Conflict().finish()
+    })
+}
+
+#[derive(Deserialize)]
+pub struct AnnouncementBody {
+    text: String,
+    #[serde(default)]
+    translations: HashMap<Locale, String>,
+}
+
+#[derive(Serialize)]
+struct AnnouncementResponse {
+    recipients: usize,
+}
+
+async fn broadcast_announcement(
+    req: HttpRequest,
+    body: web::Json<AnnouncementBody>,
+    db: web::Data<Addr<ServerActor>>,
+    config: web::Data<Arc<Config>>,
+) -> Result<HttpResponse, Error> {
+    if !is_admin(&req, &config) {
+        return Ok(HttpResponse::Unauthorized().finish());
+    }
+    let AnnouncementBody { text, translations } = body.into_inner();
+    let valid = |text: &String| !text.is_empty() && text.chars().count() <= MAX_ANNOUNCEMENT_LEN;
+    if !valid(&text) || !translations.values().all(valid) {
+        return Ok(HttpResponse::BadRequest().finish());
+    }
+
+    let recipients = db.send(server_actor::BroadcastAnnouncement {
+        text,
+        translations,
+    }).await.map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(AnnouncementResponse { recipients }))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix::Actor;
+    use actix_web::{test, App};
+    use actix_web::http::StatusCode;
+    use serde_json::{json, Value};
+
+    use crate::test_client::{settle, start_game, TestClient};
+
+    use super::*;
+
+    #[actix_rt::test]
+    async fn health_fails_during_shutdown() {
+        let db = ServerActor::default().start();
+        let mut app = test::init_service(App::new().data(db.clone()).configure(config)).await;
+
+        let res = test::call_service(&mut app, test::TestRequest::get().uri("/health").to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        db.send(server_actor::Shutdown { reason: "stop" }).await.unwrap();
+        let res = test::call_service(&mut app, test::TestRequest::get().uri("/health").to_request()).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[actix_rt::test]
+    async fn completed_games_are_recorded() {
+        let config = Arc::new(Config {
+            min_players: 2,
+            admin_token: "secret".to_string(),
+            ..Config::default()
+        });
+        let db = ServerActor::new(config.clone()).start();
+        let mut app = test::init_service(App::new().data(db.clone()).data(config.clone()).configure(super::config)).await;
+
+        let mut host = TestClient::connect(&db, &config);
+        let host_id = host.login("host").await["playerId"].clone();
+        let invite_id = host.call("room_create", json!({})).await["inviteId"].clone();
+        let mut guest = TestClient::connect(&db, &config);
+        let guest_id = guest.login("guest").await["playerId"].clone();
+        guest.call("room_join", json!({ "inviteId": invite_id })).await;
+        start_game(&mut [&mut host, &mut guest], "relay").await;
+        actix_rt::time::delay_for(std::time::Duration::from_millis(100)).await;
+        host.send_special(json!({ "type": "end_game" }));
+        guest.send_special(json!({ "type": "end_game" }));
+        settle().await;
+
+        let res = test::call_service(&mut app, test::TestRequest::get().uri("/api/admin/games").to_request()).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let req = test::TestRequest::get()
+            .uri("/api/admin/games")
+            .header("Authorization", "Bearer secret")
+            .to_request();
+        let res: Value = test::read_response_json(&mut app, req).await;
+        let games = res["games"].as_array().unwrap();
+        assert_eq!(games.len(), 1);// A single record, whoever ended it.
+        let mut players = games[0]["players"].as_array().unwrap().clone();
+        players.sort_by_key(|x| x.as_str().unwrap().to_string());
+        let mut expected = vec![host_id, guest_id];
+        expected.sort_by_key(|x| x.as_str().unwrap().to_string());
+        assert_eq!(players, expected);
+        assert_eq!(games[0]["connectionType"], "relay");
+        let duration = games[0]["durationMs"].as_u64().unwrap();
+        assert!((100..1_000).contains(&duration), "{}", duration);
+    }
+
+    #[actix_rt::test]
+    async fn persistent_rooms_survive_being_empty() {
+        let config = Arc::new(Config {
+            admin_token: "secret".to_string(),
+            ..Config::default()
+        });
+        let db = ServerActor::new(config.clone()).start();
+        let mut app = test::init_service(App::new().data(db.clone()).data(config.clone()).configure(super::config)).await;
+        let create = || test::TestRequest::post()
+            .uri("/api/admin/room")
+            .header("Authorization", "Bearer secret")
+            .set_json(&json!({ "inviteId": "bG9iYnk=" }))
+            .to_request();
+
+        let res = test::call_service(&mut app, create()).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let res = test::call_service(&mut app, create()).await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+
+        for name in &["first", "second"] {
+            let mut client = TestClient::connect(&db, &config);
+            client.join_room(name, "bG9iYnk=").await;
+            client.call("room_leave", json!({})).await;
+        }
+        settle().await;
+        let mut client = TestClient::connect(&db, &config);
+        let res = client.join_room("third", "bG9iYnk=").await;
+        assert_eq!(res["players"].as_array().unwrap().len(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn session_status_follows_the_session() {
+        let config = Arc::new(Config {
+            session_resume_ms: 100,
+            ..Config::default()
+        });
+        let db = ServerActor::new(config.clone()).start();
+        let mut app = test::init_service(App::new().data(db.clone()).configure(super::config)).await;
+        let status = |token: &Value| test::TestRequest::post()
+            .uri("/session/status")
+            .set_json(&json!({ "token": token }))
+            .to_request();
+
+        let mut client = TestClient::connect(&db, &config);
+        let token = client.login("a").await["reconnectToken"].clone();
+        client.call("room_create", json!({})).await;
+        let res: Value = test::read_response_json(&mut app, status(&token)).await;
+        assert_eq!(res["status"], "live");
+        assert_eq!(res["state"], "lobby");
+        assert!(res["roomId"].is_string());
+
+        client.disconnect();
+        settle().await;
+        let res: Value = test::read_response_json(&mut app, status(&token)).await;
+        assert_eq!(res["status"], "grace");
+        assert_eq!(res["state"], "lobby");
+
+        actix_rt::time::delay_for(std::time::Duration::from_millis(150)).await;
+        let res: Value = test::read_response_json(&mut app, status(&token)).await;
+        assert_eq!(res, json!({ "status": "expired" }));
+
+        let res = test::call_service(&mut app, status(&json!("not a token"))).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn capacity_counts_the_free_seats() {
+        let config = Arc::new(Config {
+            max_rooms: 1,
+            max_players: 2,
+            min_players: 2,
+            ..Config::default()
+        });
+        let db = ServerActor::new(config.clone()).start();
+        let mut app = test::init_service(App::new().data(db.clone()).configure(super::config)).await;
+
+        // The only room can't be created anymore, but it still has a free seat.
+        let mut first = TestClient::connect(&db, &config);
+        first.login("first").await;
+        first.call("room_find", json!({})).await;
+        let res: Value = test::read_response_json(&mut app, test::TestRequest::get().uri("/capacity").to_request()).await;
+        assert_eq!(res["rooms"], 1);
+        assert_eq!(res["maxPlayers"], 2);
+        assert_eq!(res["acceptingNew"], true);
+
+        let mut second = TestClient::connect(&db, &config);
+        second.login("second").await;
+        second.call("room_find", json!({})).await;
+        settle().await;
+        let res: Value = test::read_response_json(&mut app, test::TestRequest::get().uri("/capacity").to_request()).await;
+        assert_eq!(res["players"], 2);
+        assert_eq!(res["acceptingNew"], false);
+    }
+}
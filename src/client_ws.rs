@@ -1,48 +1,205 @@
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::convert::TryInto;
+use std::mem;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use actix::{Actor, Addr, AsyncContext, prelude::*, StreamHandler};
+use actix::dev::{SendError, ToEnvelope};
 use actix_web::{Error, HttpRequest, HttpResponse, web};
 use actix_web_actors::ws;
 use serde::Serialize;
+use serde_json::Value;
 
-use crate::protocol::{IdMessage, IdType, LoginResponse, NoData, OutEvent, OutGameMessage, OutMessage, ReceivedGameMessage, ReceivedMessage, Response, RoomCreateResponse, RoomJoinResponse, RoomFindResponse};
+use crate::clock::{Clock, SystemClock};
+use crate::config::Config;
+use crate::locale::Locale;
+use crate::protocol::{ChangeAvatarResponse, ChatEntry, IdMessage, InviteId, PlayerCosmetics, IdType, LoginResponse, NoData, OutEvent, OutGameEvent, OutGameMessage, OutMessage, ReceivedGameMessage, ReceivedMessage, Response, RoomConnectionType, RoomCreateResponse, RoomJoinResponse, RoomFindResponse};
 use crate::protocol;
-use crate::server_actor::{self, Event, GameEvent, JoinRoomResult, FindRoomResult, SendRelayMexRaw, ServerActor};
+use crate::server_actor::{self, CreateRoomResult, ErrorEvent, Event, GameEvent, JoinRoomResult, FindRoomResult, KickPlayerResult, RelayData, SpectateResult, SendRelayMexRaw, ServerActor};
 
-/// How often heartbeat pings are sent
-const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
-/// How long before lack of client response causes a timeout
-const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long the responses of requests with an idempotency key are kept to answer retries.
+const IDEMPOTENCY_WINDOW: Duration = Duration::from_secs(30);
+/// Max number of responses kept for retried requests.
+const MAX_RECENT_RESULTS: usize = 16;
+/// How many messages from the server can be waiting to be processed, past this the client is disconnected.
+const MAILBOX_CAPACITY: usize = 256;
 
 const RELAY_QUEUE_MAX_SIZE: usize = 64usize;
+/// Min time between two relay_rate_limited (or relay_disabled) errors sent to the same client.
+const RELAY_LIMIT_WARNING_INTERVAL: Duration = Duration::from_secs(1);
+/// How long a client can keep exceeding RELAY_RATE_LIMIT before being disconnected.
+const RELAY_FLOOD_TIME: Duration = Duration::from_secs(5);
 
-#[derive(PartialEq, Eq)]
+#[derive(PartialEq, Eq, Debug)]
 pub enum ClientState {
     PreLogin,// What's your name sir?
     MatchMaking,// Join or Create room (can also re-login to change name)
     Lobby,// You're in a room, prepare for battle (can also change cosmetics).
     PrePlaying(u64),// The game is started but the client hasn't acknowledged it yet.
-    Playing// Playing.
+    Playing,// Playing.
+    Spectating,// Watching a game, it receives the relay packets but can't send them.
+}
+
+impl ClientState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ClientState::PreLogin => "pre_login",
+            ClientState::MatchMaking => "matchmaking",
+            ClientState::Lobby => "lobby",
+            ClientState::PrePlaying(_) => "pre_playing",
+            ClientState::Playing => "playing",
+            ClientState::Spectating => "spectating",
+        }
+    }
+
+    /// Types of the messages that the client can send in this state, anything else is rejected before being handled.
+    pub fn allowed_messages(&self) -> &'static [&'static str] {
+        match self {
+            ClientState::PreLogin => &["login", "reconnect", "resume"],
+            ClientState::MatchMaking => &["login", "room_find", "cancel_find", "room_create", "room_join", "room_rejoin", "room_peek", "spectate_join", "capabilities"],
+            ClientState::Lobby => &["change_avatar", "room_leave", "room_start", "kick_player", "chat_send", "set_ready", "resync_request", "capabilities"],
+            ClientState::PrePlaying(_) => &["change_avatar", "room_leave", "room_start", "chat_send", "resync_request", "event_room_start_ack", "capabilities"],
+            ClientState::Playing => &[],// Only relay and special messages
+            ClientState::Spectating => &["room_leave", "capabilities"],
+        }
+    }
 }
 
 pub struct ClientWs {
     state: ClientState,
     last_hb: Instant,
+    last_activity: Instant,// Last message received, pings excluded.
     session_id: IdType,
     next_send_id: u64,
     db: Addr<ServerActor>,
+    config: Arc<Config>,
+    clock: Arc<dyn Clock>,
     relay_queue: Vec<server_actor::SendRelayMexRaw>,
+    is_host: bool,// Local copy of the host flag, kept in sync with the server's one.
+    pending_find: Option<u64>,// Generation of the RoomFind waiting for the server's answer, if any.
+    find_generation: u64,// Incremented by every RoomFind, the answers to the older ones are stale.
+    pending_login: bool,// The login is waiting for the server's answer.
+    protocol_errors: u32,// Consecutive invalid messages received.
+    recent_results: VecDeque<RecentResult>,
+    locale: Locale,
+
+    // Outbound buffer estimate: everything sent before the last answered ping has been received.
+    // Every ping carries the bytes sent before it, so a late pong only accounts for its own ping.
+    sent_bytes: u64,
+    received_bytes: u64,// Bytes sent before the last answered ping.
+    reported_buffered_bytes: u64,
+    reported_activity: Instant,
+    shed_messages: u32,// Messages dropped in this heartbeat because the server's mailbox was full.
+
+    connection_type: RoomConnectionType,// Of the last game started, nothing is relayed in peer to peer games.
+    relay_bucket: RelayBucket,
+}
+
+/// What to do with a relay packet, see RelayBucket::take.
+#[derive(PartialEq, Eq, Debug)]
+enum RelayVerdict {
+    Allowed,
+    Dropped { warn: bool },
+    Flood,
+}
+
+/// Token bucket of RELAY_RATE_LIMIT: it holds up to a second of packets and refills at the limit's rate,
+/// so a client can burst after being quiet but never sends more than the limit on average.
+struct RelayBucket {
+    tokens: f64,
+    refilled_at: Instant,
+    throttled_since: Option<Instant>,// The client has been exceeding the limit since then.
+    warned_at: Option<Instant>,
+}
+
+impl RelayBucket {
+    fn new(limit: u32, now: Instant) -> Self {
+        RelayBucket {
+            tokens: limit as f64,
+            refilled_at: now,
+            throttled_since: None,
+            warned_at: None,
+        }
+    }
+
+    /// Takes a token for a relay packet, a limit of 0 means no limit.
+    fn take(&mut self, limit: u32, now: Instant) -> RelayVerdict {
+        if limit == 0 {
+            return RelayVerdict::Allowed;
+        }
+        let capacity = limit as f64;
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * capacity).min(capacity);
+        self.refilled_at = now;
+        if self.tokens >= capacity / 2.0 {
+            // Comfortably below the limit again.
+            self.throttled_since = None;
+        }
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return RelayVerdict::Allowed;
+        }
+        let since = *self.throttled_since.get_or_insert(now);
+        if now.duration_since(since) >= RELAY_FLOOD_TIME {
+            return RelayVerdict::Flood;
+        }
+        RelayVerdict::Dropped { warn: self.should_warn(now) }
+    }
+
+    /// True if the client hasn't been warned in the last RELAY_LIMIT_WARNING_INTERVAL.
+    fn should_warn(&mut self, now: Instant) -> bool {
+        let warn = self.warned_at.is_none_or(|x| now.duration_since(x) >= RELAY_LIMIT_WARNING_INTERVAL);
+        if warn {
+            self.warned_at = Some(now);
+        }
+        warn
+    }
+}
+
+/// Response of a request with an idempotency key, sent again if the request is retried.
+struct RecentResult {
+    mtype: &'static str,
+    key: String,
+    time: Instant,
+    response: Value,
 }
 
 impl ClientWs {
-    pub fn new(db: Addr<ServerActor>) -> Self {
+    pub fn new(db: Addr<ServerActor>, config: Arc<Config>) -> Self {
+        ClientWs::with_clock(db, config, Arc::new(SystemClock))
+    }
+
+    /// Uses the given clock for the heartbeat timeout instead of the system one.
+    pub fn with_clock(db: Addr<ServerActor>, config: Arc<Config>, clock: Arc<dyn Clock>) -> Self {
+        let now = clock.now();
+        let relay_bucket = RelayBucket::new(config.relay_rate_limit, now);
         ClientWs {
             state: ClientState::PreLogin,
-            last_hb: Instant::now(),
+            last_hb: now,
+            last_activity: now,
             session_id: 0,
             next_send_id: 0,
             db,
+            config,
+            clock,
             relay_queue: Vec::new(),
+            is_host: false,
+            pending_find: None,
+            find_generation: 0,
+            pending_login: false,
+            protocol_errors: 0,
+            recent_results: VecDeque::new(),
+            locale: Locale::default(),
+            sent_bytes: 0,
+            received_bytes: 0,
+            reported_buffered_bytes: 0,
+            reported_activity: now,
+            shed_messages: 0,
+            connection_type: RoomConnectionType::ServerBroadcast,
+            relay_bucket,
         }
     }
 
@@ -50,9 +207,10 @@ impl ClientWs {
     ///
     /// also this method checks heartbeats from client
     fn start_heartbeat_checker(&self, ctx: &mut ws::WebsocketContext<Self>) {
-        ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
+        let interval = Duration::from_millis(self.config.heartbeat_interval_ms);
+        ctx.run_interval(interval, |act, ctx| {
             // check client heartbeats
-            if Instant::now().duration_since(act.last_hb) > CLIENT_TIMEOUT {
+            if act.heartbeat_expired() {
                 // heartbeat timed out
                 println!("Websocket Client heartbeat failed, disconnecting!");
 
@@ -63,8 +221,177 @@ impl ClientWs {
                 return;
             }
 
-            ctx.ping(b"");
+            act.check_lobby_idle(ctx);
+            act.report_connection_stats();
+            if act.shed_messages > 0 {
+                eprintln!("Server busy, dropped {} messages from client {}", act.shed_messages, act.session_id);
+                act.shed_messages = 0;
+            }
+
+            ctx.ping(&act.sent_bytes.to_be_bytes());
+        });
+    }
+
+    /// The warning logged when a relay packet is dropped because of the client's state, None if it can receive it.
+    fn relay_drop_warning(&self) -> Option<String> {
+        match self.state {
+            ClientState::PreLogin if self.pending_login => None,
+            // Shouldn't happen but it might if the player has just left a playing room.
+            ClientState::PreLogin | ClientState::MatchMaking | ClientState::Lobby => {
+                Some(format!("Client {} received a relay packet in state {:?}, dropping it", self.session_id, self.state))
+            },
+            _ => None,
+        }
+    }
+
+    /// The client hasn't answered the pings for longer than CLIENT_TIMEOUT_MS.
+    fn heartbeat_expired(&self) -> bool {
+        self.clock.now().duration_since(self.last_hb) > Duration::from_millis(self.config.client_timeout_ms)
+    }
+
+    /// Bytes sent to the client that it (probably) hasn't received yet.
+    fn buffered_bytes(&self) -> u64 {
+        self.sent_bytes - self.received_bytes
+    }
+
+    /// Tells the server the current outbound buffer estimate and last activity, only if they have changed.
+    fn report_connection_stats(&mut self) {
+        let bytes = self.buffered_bytes();
+        if self.state == ClientState::PreLogin || (bytes == self.reported_buffered_bytes && self.last_activity == self.reported_activity) {
+            return;
+        }
+        let res = self.db.try_send(server_actor::ReportConnectionStats {
+            id: self.session_id,
+            buffered_bytes: bytes,
+            last_activity: self.last_activity,
+        });
+        if res.is_ok() {
+            self.reported_buffered_bytes = bytes;
+            self.reported_activity = self.last_activity;
+        }
+    }
+
+    /// Sends a message that can be dropped if the server is overloaded (its mailbox is full),
+    /// the client is told once per heartbeat.
+    fn send_droppable<M>(&mut self, ctx: &mut ws::WebsocketContext<Self>, msg: M)
+        where
+            M: Message + Send + 'static,
+            M::Result: Send,
+            ServerActor: Handler<M>,
+            <ServerActor as Actor>::Context: ToEnvelope<ServerActor, M>,
+    {
+        if let Err(SendError::Full(_)) = self.db.try_send(msg) {
+            if self.shed_messages == 0 {
+                self.send_error(ctx, protocol::Error::from("server_busy".into(), None));
+            }
+            self.shed_messages += 1;
+        }
+    }
+
+    /// Counts a relay packet sent by the client, returns false if it has to be dropped because of RELAY_RATE_LIMIT.
+    /// The client is told at most once per second, and disconnected if it keeps exceeding the limit.
+    fn allow_relay(&mut self, ctx: &mut ws::WebsocketContext<Self>) -> bool {
+        if !self.connection_type.is_relayed() {
+            if self.relay_bucket.should_warn(self.clock.now()) {
+                self.send_error(ctx, protocol::Error::from("relay_disabled".into(), None));
+            }
+            return false;
+        }
+        match self.relay_bucket.take(self.config.relay_rate_limit, self.clock.now()) {
+            RelayVerdict::Allowed => true,
+            RelayVerdict::Dropped { warn } => {
+                if warn {
+                    self.send_error(ctx, protocol::Error::from("relay_rate_limited".into(), None));
+                }
+                false
+            },
+            RelayVerdict::Flood => {
+                println!("Client {} keeps flooding its room, disconnecting", self.session_id);
+                ctx.close(Some(ws::CloseReason {
+                    code: ws::CloseCode::Policy,
+                    description: Some("relay_flood".into()),
+                }));
+                ctx.stop();
+                false
+            },
+        }
+    }
+
+    /// Accounts for the bytes about to be sent, clients that don't keep up with them are disconnected
+    /// before their outbound buffer grows too much. Returns false if the client has been disconnected.
+    fn track_sent_bytes(&mut self, ctx: &mut ws::WebsocketContext<Self>, len: usize) -> bool {
+        if ctx.state() != ActorState::Running {
+            return false;// Already disconnecting.
+        }
+        self.sent_bytes += len as u64;
+
+        let max = self.config.send_buffer_high_water;
+        if max == 0 || self.buffered_bytes() <= max {
+            return true;
+        }
+        println!("Client {} has {} bytes waiting to be received, disconnecting", self.session_id, self.buffered_bytes());
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Policy,
+            description: Some("slow_consumer".into()),
+        }));
+        ctx.stop();
+        false
+    }
+
+    fn send_relay(&mut self, ctx: &mut ws::WebsocketContext<Self>, data: RelayData) {
+        match data {
+            RelayData::Text(x) => {
+                if self.track_sent_bytes(ctx, x.len()) {
+                    ctx.text(x);
+                }
+            },
+            RelayData::Binary(x) => {
+                if self.track_sent_bytes(ctx, x.len()) {
+                    ctx.binary(x);
+                }
+            },
+        }
+    }
+
+    /// Removes the player from the game if it still hasn't acknowledged its start (`start_id`) after the timeout,
+    /// its peers are told that it has left and play without it.
+    fn schedule_start_ack_check(&mut self, ctx: &mut ws::WebsocketContext<Self>, start_id: u64) {
+        let timeout = self.config.start_ack_timeout_ms;
+        if timeout == 0 {
+            return;
+        }
+        ctx.run_later(Duration::from_millis(timeout), move |act, ctx| {
+            if act.state != ClientState::PrePlaying(start_id) {
+                return;
+            }
+            println!("Player {} hasn't acknowledged the start of its game, kicking it", act.session_id);
+            act.db.do_send(server_actor::LeaveRoom {
+                id: act.session_id
+            });
+            act.state = ClientState::MatchMaking;
+            act.is_host = false;
+            act.relay_queue.clear();
+            act.send_message(ctx, &OutEvent::EventKicked { reason: "start_ack_timeout" });
+        });
+    }
+
+    /// Kicks the player out of its lobby if it hasn't sent anything for too long, so that it doesn't hold a seat.
+    fn check_lobby_idle(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        let timeout = self.config.lobby_idle_timeout_ms;
+        if timeout == 0 || self.state != ClientState::Lobby {
+            return;
+        }
+        if self.clock.now().duration_since(self.last_activity) < Duration::from_millis(timeout) {
+            return;
+        }
+
+        println!("Player {} has been idle in its lobby, kicking it", self.session_id);
+        self.db.do_send(server_actor::LeaveRoom {
+            id: self.session_id
         });
+        self.state = ClientState::MatchMaking;
+        self.is_host = false;
+        self.send_message(ctx, &OutEvent::EventKicked { reason: "idle" });
     }
 }
 
@@ -72,15 +399,17 @@ impl Actor for ClientWs {
     type Context = ws::WebsocketContext<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.set_mailbox_capacity(MAILBOX_CAPACITY);
         self.start_heartbeat_checker(ctx)
     }
 
-    fn stopping(&mut self, _: &mut Self::Context) -> Running {
+    fn stopping(&mut self, ctx: &mut Self::Context) -> Running {
         match self.state {
             ClientState::PreLogin => {},
             _ => {
                 self.db.do_send(server_actor::Disconnect {
                     id: self.session_id,
+                    addr: ctx.address(),
                 });
             },
         }
@@ -95,14 +424,85 @@ impl ClientWs {
         id.into()
     }
 
+    /// Parses the invite id of a request, answering with an "invalid_invite_id" result if malformed.
+    fn parse_invite_id(&mut self, ctx: &mut <Self as Actor>::Context, id: u64, ptype: &'static str, invite_id: &str) -> Option<InviteId> {
+        match InviteId::parse(invite_id) {
+            Ok(x) => Some(x),
+            Err(_) => {
+                let pkt = Response::from(
+                    id, ptype.into(), Some("invalid_invite_id".into()), NoData {}
+                );
+                self.send_message(ctx, &pkt);
+                None
+            },
+        }
+    }
+
+    /// Sends the response of a request that could be retried, it's kept if the request has an idempotency key.
+    fn send_idempotent<T: Serialize>(&mut self, ctx: &mut <Self as Actor>::Context, mtype: &'static str, key: &Option<String>, pkt: &Response<T>) {
+        self.send_message(ctx, pkt);
+
+        if let Some(key) = key {
+            if self.recent_results.len() >= MAX_RECENT_RESULTS {
+                self.recent_results.pop_front();
+            }
+            self.recent_results.push_back(RecentResult {
+                mtype,
+                key: key.clone(),
+                time: self.clock.now(),
+                response: serde_json::to_value(pkt).expect("Error serializing message"),
+            });
+        }
+    }
+
+    /// Sends again the response of a recent request with the same type and idempotency key, returns false if none.
+    fn replay_result(&mut self, ctx: &mut <Self as Actor>::Context, id: u64, mtype: &str, key: &str) -> bool {
+        let now = self.clock.now();
+        self.recent_results.retain(|x| now.duration_since(x.time) < IDEMPOTENCY_WINDOW);
+
+        let mut response = match self.recent_results.iter().find(|x| x.mtype == mtype && x.key == key) {
+            Some(x) => x.response.clone(),
+            None => return false,
+        };
+        response["requestId"] = id.into();
+        self.send_message(ctx, &response);
+        true
+    }
+
+    /// Sends the error for an invalid message, clients that keep sending them are disconnected.
+    pub fn send_protocol_error(&mut self, ctx: &mut <Self as Actor>::Context, err: protocol::Error) {
+        self.send_error(ctx, err);
+
+        self.protocol_errors += 1;
+        let max = self.config.max_protocol_errors;
+        if max > 0 && self.protocol_errors >= max {
+            println!("Client {} sent {} invalid messages in a row, disconnecting", self.session_id, self.protocol_errors);
+            ctx.close(Some(ws::CloseReason {
+                code: ws::CloseCode::Policy,
+                description: Some("too_many_errors".into()),
+            }));
+            ctx.stop();
+        }
+    }
+
+    /// Sends an error, describing it in the client's language if it has no specific message.
+    pub fn send_error(&mut self, ctx: &mut <Self as Actor>::Context, mut err: protocol::Error) -> u64 {
+        if err.error_message.is_none() {
+            err.error_message = self.locale.error_message(&err.error).map(Cow::from);
+        }
+        self.send_message(ctx, &err)
+    }
+
     pub fn send_message<T: ?Sized + Serialize> (&mut self, ctx: &mut <Self as Actor>::Context, inner: &T) -> u64 {
         let id = self.allocate_id();
         let mex = OutMessage {
-            id, mex: inner
+            id,
+            ts: if self.config.message_timestamps { Some(server_actor::now_millis()) } else { None },
+            mex: inner
         };
 
         let mut writer = Vec::with_capacity(128);
-        if self.state == ClientState::Playing {
+        if self.state == ClientState::Playing || self.state == ClientState::Spectating {
             // Special message
             writer.push(b'#');
         }
@@ -111,12 +511,39 @@ impl ClientWs {
         let text = unsafe {// It's safe? well it copies serde's method so I hope it is.
             String::from_utf8_unchecked(writer)
         };
-        ctx.text(text);
+        if self.track_sent_bytes(ctx, text.len()) {
+            ctx.text(text);
+        }
         id
     }
 
+    /// Sends the last chat messages of the room that has just been joined, if there are any.
+    fn send_chat_history(&mut self, ctx: &mut <Self as Actor>::Context, messages: Vec<ChatEntry>) {
+        if !messages.is_empty() {
+            self.send_message(ctx, &OutEvent::EventChatHistory { messages });
+        }
+    }
+
+    /// Sends an error back if the cosmetics are not valid.
+    fn check_cosmetics(&mut self, ctx: &mut <Self as Actor>::Context, id: u64, cosmetics: &PlayerCosmetics) -> bool {
+        match cosmetics.validate(self.config.avatar_count) {
+            Ok(()) => true,
+            Err(err) => {
+                self.send_error(ctx, protocol::Error::from_origin(id, err.into(), None));
+                false
+            },
+        }
+    }
+
     pub fn handle_message_login(&mut self, ctx: &mut <Self as Actor>::Context, id: u64, mex: ReceivedMessage) {
         if let ReceivedMessage::Login { details } = mex {
+            self.locale = details.locale;
+            if !self.check_cosmetics(ctx, id, &details.cosmetics) {
+                return;
+            }
+            // Don't block the actor (pings included) while the server registers the session,
+            // any other message is rejected until the login is complete.
+            self.pending_login = true;
             self.db.send(server_actor::RegisterSession {
                 id: None,
                 addr: ctx.address(),
@@ -124,33 +551,166 @@ impl ClientWs {
             })
                 .into_actor(self)
                 .then(move |res, act, ctx| {
-                    match res {
-                        Ok(res) => act.session_id = res,
+                    let reconnect_token = match res {
+                        Ok(Some((session_id, token))) => {
+                            act.session_id = session_id;
+                            token
+                        },
                         _ => {
                             // something is wrong with chat server
                             ctx.stop();
                             return fut::ready(());
                         },
-                    }
+                    };
                     let res = Response::ok(
                         id, "login_response".into(),
                         LoginResponse {
                             player_id: act.session_id.into(),
+                            reconnect_token,
                         }
                     );
                     act.state = ClientState::MatchMaking;
+                    act.pending_login = false;
                     act.send_message(ctx, &res);
                     fut::ready(())
                 })
-                .wait(ctx);
+                .spawn(ctx);
+        } else if let ReceivedMessage::Reconnect { player_id, room_id, token } = mex {
+            if !protocol::is_valid_reconnect_token(&token) {
+                let pkt = Response::from(id, "reconnect_response".into(), Some("invalid_token".into()), NoData {});
+                self.send_message(ctx, &pkt);
+                return;
+            }
+            // Like the login nothing else is accepted until the server has answered.
+            self.pending_login = true;
+            self.db.send(server_actor::ReconnectSession {
+                player_id: player_id.0,
+                room_id: room_id.0,
+                token,
+                addr: ctx.address(),
+            })
+                .into_actor(self)
+                .then(move |res, act, ctx| {
+                    act.pending_login = false;
+                    let res = match res {
+                        Ok(res) => res,
+                        _ => {
+                            ctx.stop();
+                            return fut::ready(());
+                        },
+                    };
+                    let ptype = "reconnect_response".into();
+                    match res {
+                        Some(info) => {
+                            act.session_id = player_id.0;
+                            act.is_host = info.is_host;
+                            act.locale = info.locale;
+                            act.send_message(ctx, &Response::ok(id, ptype, NoData {}));
+                            act.state = ClientState::Playing;
+                            // Relays that have arrived before the answer.
+                            for x in mem::take(&mut act.relay_queue) {
+                                act.send_relay(ctx, x.data);
+                            }
+                        },
+                        None => {
+                            let pkt = Response::from(
+                                id, ptype, Some("session_not_found".into()), NoData {}
+                            );
+                            act.send_message(ctx, &pkt);
+                        },
+                    }
+                    fut::ready(())
+                })
+                .spawn(ctx);
+        } else if let ReceivedMessage::Resume { token } = mex {
+            if !protocol::is_valid_reconnect_token(&token) {
+                let pkt = Response::from(id, "resume_response".into(), Some("invalid_token".into()), NoData {});
+                self.send_message(ctx, &pkt);
+                return;
+            }
+            self.pending_login = true;
+            self.db.send(server_actor::ResumeSession {
+                token,
+                addr: ctx.address(),
+            })
+                .into_actor(self)
+                .then(move |res, act, ctx| {
+                    act.pending_login = false;
+                    let res = match res {
+                        Ok(res) => res,
+                        _ => {
+                            ctx.stop();
+                            return fut::ready(());
+                        },
+                    };
+                    let ptype = "resume_response".into();
+                    let info = match res {
+                        Some(x) => x,
+                        None => {
+                            let pkt = Response::from(
+                                id, ptype, Some("session_not_found".into()), NoData {}
+                            );
+                            act.send_message(ctx, &pkt);
+                            return fut::ready(());
+                        },
+                    };
+                    act.session_id = info.player_id;
+                    act.is_host = info.player.is_host;
+                    act.locale = info.locale;
+                    let state = if info.in_game {
+                        ClientState::Playing
+                    } else if info.spectating {
+                        ClientState::Spectating
+                    } else if info.players.is_some() {
+                        ClientState::Lobby
+                    } else {
+                        ClientState::MatchMaking
+                    };
+                    let resp = protocol::ResumeResponse {
+                        player_id: info.player_id.into(),
+                        state: state.name(),
+                        player: info.player,
+                        players: info.players,
+                    };
+                    act.send_message(ctx, &Response::ok(id, ptype, resp));
+                    act.state = state;
+                    if act.state == ClientState::Playing {
+                        // Relays that have arrived before the answer.
+                        for x in mem::take(&mut act.relay_queue) {
+                            act.send_relay(ctx, x.data);
+                        }
+                    } else {
+                        act.relay_queue.clear();
+                    }
+                    fut::ready(())
+                })
+                .spawn(ctx);
         } else {
-            self.send_message(ctx, &protocol::Error::from_origin(id, "Login Required".into(), None));
+            self.send_error(ctx, protocol::Error::from_origin(id, "Login Required".into(), None));
         }
     }
 
     pub fn handle_message_matchmaking(&mut self, ctx: &mut <Self as Actor>::Context, id: u64, mex: ReceivedMessage) {
+        if self.pending_find.is_some() {
+            match mex {
+                ReceivedMessage::CancelFind {} => {
+                    // The find response will free the room slot (if any).
+                    self.pending_find = None;
+                    self.send_message(ctx, &Response::ok(id, "cancel_find_response".into(), NoData {}));
+                },
+                _ => {
+                    self.send_error(ctx, protocol::Error::from_origin(id, "find_pending".into(), None));
+                },
+            }
+            return;
+        }
+
         match mex {
             ReceivedMessage::Login { details } => {
+                self.locale = details.locale;
+                if !self.check_cosmetics(ctx, id, &details.cosmetics) {
+                    return;
+                }
                 self.db.send(server_actor::RegisterSession {
                     id: Some(self.session_id),
                     addr: ctx.address(),
@@ -158,18 +718,27 @@ impl ClientWs {
                 })
                     .into_actor(self)
                     .then(move |res, act, ctx| {
-                        match res {
-                            Ok(_) => {},
+                        let reconnect_token = match res {
+                            Ok(Some((_, token))) => token,
+                            Ok(None) => {
+                                // The server still has the player in a room (ex. it has just been joined).
+                                let res = Response::from(
+                                    id, "login_response".into(), Some("cannot_relogin_in_room".into()), NoData {}
+                                );
+                                act.send_message(ctx, &res);
+                                return fut::ready(());
+                            },
                             _ => {
                                 // something is wrong with chat server
                                 ctx.stop();
                                 return fut::ready(());
                             },
-                        }
+                        };
                         let res = Response::ok(
                             id, "login_response".into(),
                             LoginResponse {
                                 player_id: act.session_id.into(),
+                                reconnect_token,
                             }
                         );
                         act.send_message(ctx, &res);
@@ -179,6 +748,10 @@ impl ClientWs {
                     .wait(ctx);
             },
             ReceivedMessage::RoomFind {} => {
+                // Don't block the actor so that the find can be canceled while it's pending.
+                self.find_generation += 1;
+                let generation = self.find_generation;
+                self.pending_find = Some(generation);
                 self.db.send(server_actor::FindRoom {
                     id: self.session_id
                 })
@@ -193,8 +766,27 @@ impl ClientWs {
                         };
 
                         let pkt_type = "room_find_response".into();
+                        if act.pending_find != Some(generation) {
+                            // Canceled, give back the room slot unless a newer find has already moved the player
+                            // (its FindRoom is processed before our LeaveRoom would be).
+                            if let FindRoomResult::Success { .. } = res {
+                                if act.find_generation == generation {
+                                    act.db.do_send(server_actor::LeaveRoom {
+                                        id: act.session_id
+                                    });
+                                }
+                            }
+                            let pkt = Response::from(
+                                id, pkt_type,
+                                Some("canceled".into()), NoData {}
+                            );
+                            act.send_message(ctx, &pkt);
+                            return fut::ready(());
+                        }
+                        act.pending_find = None;
+
                         match res {
-                            FindRoomResult::Success { players, room_id, just_created } => {
+                            FindRoomResult::Success { players, room_id, chat_history, just_created } => {
                                 let pkt = Response::ok(
                                     id, pkt_type,
                                     RoomFindResponse {
@@ -204,7 +796,10 @@ impl ClientWs {
                                     }
                                 );
                                 act.send_message(ctx, &pkt);
+                                act.send_chat_history(ctx, chat_history);
                                 act.state = ClientState::Lobby;
+                                // If the room has just been created we're its only player (and so its host).
+                                act.is_host = just_created;
                             },
                             FindRoomResult::GameIsFull => {
                                 // TODO
@@ -213,13 +808,23 @@ impl ClientWs {
                                     Some("game_is_full".into()), NoData {}
                                 );
                                 act.send_message(ctx, &pkt);
-                            }
+                            },
+                            FindRoomResult::SessionNotFound => {
+                                ctx.stop();
+                            },
                         }
                         fut::ready(())
                     })
-                    .wait(ctx)
+                    .spawn(ctx);
+            },
+            ReceivedMessage::CancelFind {} => {
+                let pkt = Response::from(
+                    id, "cancel_find_response".into(),
+                    Some("no_pending_find".into()), NoData {}
+                );
+                self.send_message(ctx, &pkt);
             },
-            ReceivedMessage::RoomCreate {} => {
+            ReceivedMessage::RoomCreate { idempotency_key } => {
                 self.db.send(server_actor::CreateRoom {
                     id: self.session_id
                 })
@@ -233,23 +838,51 @@ impl ClientWs {
                                 return fut::ready(());
                             },
                         };
-                        let pkt = Response::ok(
-                            id, "room_create_response".into(),
-                            RoomCreateResponse {
-                                players: [res.player],
-                                invite_id: res.room_id.into(),
-                            }
-                        );
-                        act.send_message(ctx, &pkt);
-                        act.state = ClientState::Lobby;
+                        let ptype = "room_create_response".into();
+                        match res {
+                            CreateRoomResult::Success { room_id, player } => {
+                                let pkt = Response::ok(
+                                    id, ptype,
+                                    RoomCreateResponse {
+                                        players: [player],
+                                        invite_id: InviteId {
+                                            id: room_id,
+                                            len: act.config.invite_id_bytes,
+                                        },
+                                    }
+                                );
+                                act.send_idempotent(ctx, "room_create", &idempotency_key, &pkt);
+                                act.state = ClientState::Lobby;
+                                act.is_host = true;
+                            },
+                            CreateRoomResult::ServerFull => {
+                                let pkt = Response::from(
+                                    id, ptype, Some("server_full".into()), NoData {}
+                                );
+                                act.send_idempotent(ctx, "room_create", &idempotency_key, &pkt);
+                            },
+                            CreateRoomResult::RateLimited => {
+                                let pkt = Response::from(
+                                    id, ptype, Some("rate_limited".into()), NoData {}
+                                );
+                                act.send_idempotent(ctx, "room_create", &idempotency_key, &pkt);
+                            },
+                            CreateRoomResult::SessionNotFound => {
+                                ctx.stop();
+                            },
+                        }
 
                         fut::ready(())
                     }).wait(ctx);
             },
-            ReceivedMessage::RoomJoin { invite_id } => {
+            ReceivedMessage::RoomJoin { invite_id, idempotency_key } => {
+                let invite_id = match self.parse_invite_id(ctx, id, "room_join_response", &invite_id) {
+                    Some(x) => x,
+                    None => return,
+                };
                 self.db.send(server_actor::JoinRoom {
                     id: self.session_id,
-                    room_id: invite_id.into(),
+                    room_id: invite_id.id,
                 })
                     .into_actor(self)
                     .then(move |res, act, ctx| {
@@ -263,39 +896,151 @@ impl ClientWs {
                         };
                         let ptype = "room_join_response".into();
                         match res {
-                            JoinRoomResult::Success(players) => {
+                            JoinRoomResult::Success(players, chat_history) => {
+                                // Only the first player of an empty (warm) room becomes its host.
+                                act.is_host = players.iter().any(|x| x.id.0 == act.session_id && x.is_host);
                                 let pkt = Response::ok(
                                     id, ptype,
                                     RoomJoinResponse { players }
                                 );
-                                act.send_message(ctx, &pkt);
+                                act.send_idempotent(ctx, "room_join", &idempotency_key, &pkt);
+                                act.send_chat_history(ctx, chat_history);
                                 act.state = ClientState::Lobby;
                             }
                             JoinRoomResult::RoomNotFound => {
                                 let pkt = Response::from(
                                     id, ptype, Some("room_not_found".into()), NoData {}
                                 );
-                                act.send_message(ctx, &pkt);
+                                act.send_idempotent(ctx, "room_join", &idempotency_key, &pkt);
                             },
                             JoinRoomResult::AlreadyPlaying => {
                                 let pkt = Response::from(
                                     id, ptype, Some("already_playing".into()), NoData {}
                                 );
-                                act.send_message(ctx, &pkt);
+                                act.send_idempotent(ctx, "room_join", &idempotency_key, &pkt);
                             },
                             JoinRoomResult::RoomIsFull => {
                                 let pkt = Response::from(
                                     id, ptype, Some("room_is_full".into()), NoData {}
                                 );
-                                act.send_message(ctx, &pkt);
+                                act.send_idempotent(ctx, "room_join", &idempotency_key, &pkt);
                             }
                         }
                         fut::ready(())
                     })
                     .wait(ctx);
             },
+            ReceivedMessage::SpectateJoin { room_id, join_next } => {
+                self.db.send(server_actor::SpectateRoom {
+                    id: self.session_id,
+                    room_id: room_id.0,
+                    join_next,
+                })
+                    .into_actor(self)
+                    .then(move |res, act, ctx| {
+                        let res = match res {
+                            Ok(res) => res,
+                            _ => {
+                                ctx.stop();
+                                return fut::ready(());
+                            },
+                        };
+                        let ptype = "spectate_join_response".into();
+                        let error = match res {
+                            SpectateResult::Success(players) => {
+                                let pkt = Response::ok(
+                                    id, ptype,
+                                    RoomJoinResponse { players }
+                                );
+                                act.send_message(ctx, &pkt);
+                                act.state = ClientState::Spectating;
+                                return fut::ready(());
+                            },
+                            SpectateResult::RoomNotFound => "room_not_found",
+                            SpectateResult::NotPlaying => "not_playing",
+                        };
+                        let pkt = Response::from(
+                            id, ptype, Some(error.into()), NoData {}
+                        );
+                        act.send_message(ctx, &pkt);
+                        fut::ready(())
+                    })
+                    .wait(ctx);
+            },
+            ReceivedMessage::RoomRejoin {} => {
+                self.db.send(server_actor::RejoinRoom {
+                    id: self.session_id,
+                })
+                    .into_actor(self)
+                    .then(move |res, act, ctx| {
+                        let res = match res {
+                            Ok(res) => res,
+                            _ => {
+                                ctx.stop();
+                                return fut::ready(());
+                            },
+                        };
+                        let ptype = "room_rejoin_response".into();
+                        let error = match res {
+                            JoinRoomResult::Success(players, chat_history) => {
+                                // The host changes when it leaves, we can only be the host of a rejoined room if it was empty (warm).
+                                act.is_host = players.iter().any(|x| x.id.0 == act.session_id && x.is_host);
+                                let pkt = Response::ok(
+                                    id, ptype,
+                                    RoomJoinResponse { players }
+                                );
+                                act.send_message(ctx, &pkt);
+                                act.send_chat_history(ctx, chat_history);
+                                act.state = ClientState::Lobby;
+                                return fut::ready(());
+                            },
+                            JoinRoomResult::RoomNotFound => "room_not_found",
+                            JoinRoomResult::AlreadyPlaying => "already_playing",
+                            JoinRoomResult::RoomIsFull => "room_is_full",
+                        };
+                        let pkt = Response::from(
+                            id, ptype, Some(error.into()), NoData {}
+                        );
+                        act.send_message(ctx, &pkt);
+                        fut::ready(())
+                    })
+                    .wait(ctx);
+            },
+            ReceivedMessage::RoomPeek { invite_id } => {
+                let invite_id = match self.parse_invite_id(ctx, id, "room_peek_response", &invite_id) {
+                    Some(x) => x,
+                    None => return,
+                };
+                self.db.send(server_actor::PeekRoom {
+                    room_id: invite_id.id,
+                })
+                    .into_actor(self)
+                    .then(move |res, act, ctx| {
+                        let res = match res {
+                            Ok(res) => res,
+                            _ => {
+                                ctx.stop();
+                                return fut::ready(());
+                            },
+                        };
+                        let ptype = "room_peek_response".into();
+                        match res {
+                            Some(peek) => {
+                                act.send_message(ctx, &Response::ok(id, ptype, peek));
+                            },
+                            None => {
+                                let pkt = Response::from(
+                                    id, ptype, Some("room_not_found".into()), NoData {}
+                                );
+                                act.send_message(ctx, &pkt);
+                            },
+                        }
+                        fut::ready(())
+                    })
+                    .wait(ctx);
+            },
             _ => {
-                self.send_message(ctx, &protocol::Error::from_origin(id, "Invalid message type".into(), None));
+                self.send_error(ctx, protocol::Error::from_origin(id, "Invalid message type".into(), None));
             }
         }
     }
@@ -303,45 +1048,173 @@ impl ClientWs {
     pub fn handle_message_lobby(&mut self, ctx: &mut <Self as Actor>::Context, id: u64, mex: ReceivedMessage) {
         match mex {
             ReceivedMessage::ChangeAvatar { cosmetics } => {
-                self.db.do_send(server_actor::EditCosmetics {
+                if !self.config.lobby_cosmetics_changes {
+                    self.send_error(ctx, protocol::Error::from_origin(id, "cosmetics_locked".into(), None));
+                    return;
+                }
+                if !self.check_cosmetics(ctx, id, &cosmetics) {
+                    return;
+                }
+                // Answered even if nothing changed, so that the client knows its cosmetics are applied.
+                self.db.send(server_actor::EditCosmetics {
                     id: self.session_id,
                     obj: cosmetics,
                 })
+                    .into_actor(self)
+                    .then(move |res, act, ctx| {
+                        let changed = match res {
+                            Ok(x) => x,
+                            _ => {
+                                ctx.stop();
+                                return fut::ready(());
+                            },
+                        };
+                        let pkt = Response::ok(id, "change_avatar_response".into(), ChangeAvatarResponse { changed });
+                        act.send_message(ctx, &pkt);
+                        fut::ready(())
+                    })
+                    .wait(ctx);
             },
             ReceivedMessage::RoomLeave {} => {
                 self.db.do_send(server_actor::LeaveRoom {
                     id: self.session_id
                 });
                 self.state = ClientState::MatchMaking;
+                self.is_host = false;
                 self.send_message(ctx, &Response::ok(id, "room_leave_response".into(), NoData {}));
             },
             ReceivedMessage::RoomStart { connection_type } => {
+                if !self.is_host {
+                    self.send_error(ctx, protocol::Error::from_origin(id, "Host only".into(), None));
+                    return;
+                }
+                if connection_type == RoomConnectionType::Unsupported {
+                    self.send_error(ctx, protocol::Error::from_origin(id, "unsupported_connection_type".into(), None));
+                    return;
+                }
                 self.db.do_send(server_actor::StartRoom {
                     id: self.session_id,
                     conn_type: connection_type
                 });
             },
+            ReceivedMessage::KickPlayer { player } => {
+                self.db.send(server_actor::KickPlayer {
+                    host_id: self.session_id,
+                    target_id: player.0,
+                })
+                    .into_actor(self)
+                    .then(move |res, act, ctx| {
+                        let res = match res {
+                            Ok(res) => res,
+                            _ => {
+                                ctx.stop();
+                                return fut::ready(());
+                            },
+                        };
+                        let result = match res {
+                            KickPlayerResult::Success => "ok",
+                            KickPlayerResult::NotHost => "not_host",
+                            KickPlayerResult::PlayerNotFound => "player_not_found",
+                            KickPlayerResult::AlreadyPlaying => "already_playing",
+                        };
+                        let pkt = Response::from(
+                            id, "kick_player_response".into(), Some(result.into()), NoData {}
+                        );
+                        act.send_message(ctx, &pkt);
+                        fut::ready(())
+                    })
+                    .wait(ctx);
+            },
+            ReceivedMessage::ChatSend { text } => {
+                let text = match protocol::validate_chat_text(&text) {
+                    Ok(x) => x.to_string(),
+                    Err(err) => {
+                        self.send_error(ctx, protocol::Error::from_origin(id, err.into(), None));
+                        return;
+                    },
+                };
+                self.db.do_send(server_actor::ChatMessage {
+                    sender_id: self.session_id,
+                    text,
+                });
+            },
+            ReceivedMessage::SetReady { ready } => {
+                self.db.do_send(server_actor::SetReady {
+                    id: self.session_id,
+                    ready,
+                });
+            },
+            ReceivedMessage::ResyncRequest { last_seen_id } => {
+                // Nothing has been missed if the client has processed the latest message.
+                let up_to_date = self.next_send_id > 0 && last_seen_id == Some(self.next_send_id - 1);
+                self.db.send(server_actor::GetRoster {
+                    id: self.session_id,
+                })
+                    .into_actor(self)
+                    .then(move |res, act, ctx| {
+                        let res = match res {
+                            Ok(res) => res,
+                            _ => {
+                                ctx.stop();
+                                return fut::ready(());
+                            },
+                        };
+                        let ptype = "resync_response".into();
+                        match res {
+                            Some(players) => {
+                                act.is_host = players.iter().any(|x| x.id.0 == act.session_id && x.is_host);
+                                let resp = protocol::ResyncResponse {
+                                    state: act.state.name(),
+                                    up_to_date,
+                                    players: if up_to_date { None } else { Some(players) },
+                                };
+                                act.send_message(ctx, &Response::ok(id, ptype, resp));
+                            },
+                            None => {
+                                let pkt = Response::from(
+                                    id, ptype, Some("room_not_found".into()), NoData {}
+                                );
+                                act.send_message(ctx, &pkt);
+                            },
+                        }
+                        fut::ready(())
+                    })
+                    .wait(ctx);
+            },
             ReceivedMessage::EventRoomStartAck { request_id } => {
                 if let ClientState::PrePlaying(res_id) = &self.state {
                     if *res_id == request_id {
                         self.state = ClientState::Playing;
-                        for x in self.relay_queue.drain(..) {
-                            ctx.text(x.data);
+                        // The actor handles one message at a time so the relays that arrive now are only handled
+                        // after the queue has been drained, they can't overtake the queued ones.
+                        for x in mem::take(&mut self.relay_queue) {
+                            self.send_relay(ctx, x.data);
                         }
                     } else {
-                        self.send_message(ctx, &protocol::Error::from_origin(id, "Invalid request_id".into(), None));
+                        // Probably the id of another message, the client stays in pre_playing and can ack again.
+                        let msg = format!("requestId must be the id of the event_room_start message ({})", res_id);
+                        self.send_error(ctx, protocol::Error::from_origin(id, "Invalid request_id".into(), Some(msg.into())));
                     }
                 } else {
-                    self.send_message(ctx, &protocol::Error::from_origin(id, "Invalid state".into(), Some("No message to acknowledge".into())));
+                    self.send_error(ctx, protocol::Error::from_origin(id, "Invalid state".into(), Some("No message to acknowledge".into())));
                 }
             },
             _ => {
-                self.send_message(ctx, &protocol::Error::from_origin(id, "Invalid message type".into(), None));
+                self.send_error(ctx, protocol::Error::from_origin(id, "Invalid message type".into(), None));
             }
         }
     }
 
     pub fn handle_message(&mut self, ctx: &mut <Self as Actor>::Context, id: u64, mex: ReceivedMessage) {
+        if let ReceivedMessage::Capabilities {} = mex {
+            // Answered the same way in every state, even while a find is pending.
+            let resp = protocol::CapabilitiesResponse {
+                state: self.state.name(),
+                actions: self.state.allowed_messages(),
+            };
+            self.send_message(ctx, &Response::ok(id, "capabilities_response".into(), resp));
+            return;
+        }
         match &self.state {
             ClientState::PreLogin => {
                 self.handle_message_login(ctx, id, mex);
@@ -353,6 +1226,25 @@ impl ClientWs {
                 self.handle_message_lobby(ctx, id, mex);
             },
             ClientState::Playing => {},
+            ClientState::Spectating => {
+                self.handle_message_spectating(ctx, id, mex);
+            },
+        }
+    }
+
+    pub fn handle_message_spectating(&mut self, ctx: &mut <Self as Actor>::Context, id: u64, mex: ReceivedMessage) {
+        match mex {
+            ReceivedMessage::RoomLeave {} => {
+                self.db.do_send(server_actor::StopSpectating {
+                    id: self.session_id
+                });
+                self.state = ClientState::MatchMaking;
+                let pkt = Response::ok(id, "room_leave_response".into(), NoData {});
+                self.send_message(ctx, &pkt);
+            },
+            _ => {
+                self.send_error(ctx, protocol::Error::from_origin(id, "Invalid message type".into(), None));
+            }
         }
     }
 
@@ -369,10 +1261,11 @@ impl ClientWs {
                 Ok(x) => x,
                 Err(_) => {
                     let err = protocol::Error::from("Invalid special Json".into(), None);
-                    self.send_message(ctx, &err);
+                    self.send_protocol_error(ctx, err);
                     return;
                 },
             };
+            self.protocol_errors = 0;
 
             match mex {
                 ReceivedGameMessage::EndGame {} => {
@@ -400,9 +1293,33 @@ impl ClientWs {
                         })
                         .wait(ctx);
                 },
+                ReceivedGameMessage::EndTurn {} => {
+                    self.db.do_send(server_actor::EndTurn {
+                        id: self.session_id,
+                    });
+                },
+                ReceivedGameMessage::Pause {} => {
+                    self.db.do_send(server_actor::PauseGame {
+                        id: self.session_id,
+                        paused: true,
+                    });
+                },
+                ReceivedGameMessage::Resume {} => {
+                    self.db.do_send(server_actor::PauseGame {
+                        id: self.session_id,
+                        paused: false,
+                    });
+                },
+                ReceivedGameMessage::RelayAck { sender, ack_id } => {
+                    self.db.do_send(server_actor::RelayAck {
+                        id: self.session_id,
+                        sender: sender.0,
+                        ack_id,
+                    });
+                },
             };
-        } else {
-            self.db.do_send(server_actor::SendRelayMex {
+        } else if self.allow_relay(ctx) {
+            self.send_droppable(ctx, server_actor::SendRelayMex {
                 sender_id: self.session_id,
                 data: text
             });
@@ -416,8 +1333,33 @@ impl Handler<Event> for ClientWs {
     fn handle(&mut self, msg: Event, ctx: &mut <Self as Actor>::Context) -> Self::Result {
         let id = self.send_message(ctx, &msg.0);
 
-        if let OutEvent::EventRoomStart { .. } = msg.0 {
-            self.state = ClientState::PrePlaying(id);
+        match msg.0 {
+            OutEvent::EventRoomStart { connection_type, .. } => {
+                self.connection_type = connection_type;
+                self.state = ClientState::PrePlaying(id);
+                self.schedule_start_ack_check(ctx, id);
+            },
+            OutEvent::EventPlayerLeft { new_host: Some(new_host), .. } => {
+                self.is_host = new_host.0 == self.session_id;
+            },
+            OutEvent::EventHostAssigned {} => {
+                self.is_host = true;
+            },
+            OutEvent::EventSpectateEnded { players: Some(players) } => {
+                // Joined the room for the next game.
+                self.state = ClientState::Lobby;
+                self.is_host = players.iter().any(|x| x.id.0 == self.session_id && x.is_host);
+                self.last_activity = self.clock.now();
+            },
+            OutEvent::EventSpectateEnded { players: None } => {
+                self.state = ClientState::MatchMaking;
+            },
+            OutEvent::EventKicked { .. } => {
+                // The server has already removed the player from the room.
+                self.state = ClientState::MatchMaking;
+                self.is_host = false;
+            },
+            _ => {},
         }
     }
 }
@@ -427,6 +1369,55 @@ impl Handler<GameEvent> for ClientWs {
 
     fn handle(&mut self, msg: GameEvent, ctx: &mut <Self as Actor>::Context) -> Self::Result {
         self.send_message(ctx, &msg.0);
+
+        if let OutGameEvent::PlayerLeft { new_host: Some(new_host), .. } = &msg.0 {
+            self.is_host = new_host.0 == self.session_id;
+        }
+    }
+}
+
+impl Handler<server_actor::Unresponsive> for ClientWs {
+    type Result = ();
+
+    fn handle(&mut self, _: server_actor::Unresponsive, ctx: &mut <Self as Actor>::Context) -> Self::Result {
+        ctx.stop();
+    }
+}
+
+impl Handler<server_actor::SessionReplaced> for ClientWs {
+    type Result = ();
+
+    fn handle(&mut self, _: server_actor::SessionReplaced, ctx: &mut <Self as Actor>::Context) -> Self::Result {
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Policy,
+            description: Some("session_replaced".into()),
+        }));
+        ctx.stop();
+    }
+}
+
+impl Handler<server_actor::ServerShutdown> for ClientWs {
+    type Result = ();
+
+    fn handle(&mut self, msg: server_actor::ServerShutdown, ctx: &mut <Self as Actor>::Context) -> Self::Result {
+        self.send_message(ctx, &OutEvent::EventServerShutdown { reason: msg.reason });
+        let code = match msg.reason {
+            "restart" => ws::CloseCode::Restart,
+            _ => ws::CloseCode::Away,
+        };
+        ctx.close(Some(ws::CloseReason {
+            code,
+            description: Some("server_shutdown".into()),
+        }));
+        ctx.stop();
+    }
+}
+
+impl Handler<ErrorEvent> for ClientWs {
+    type Result = ();
+
+    fn handle(&mut self, msg: ErrorEvent, ctx: &mut <Self as Actor>::Context) -> Self::Result {
+        self.send_error(ctx, protocol::Error::from(msg.0.into(), None));
     }
 }
 
@@ -434,10 +1425,18 @@ impl Handler<server_actor::SendRelayMexRaw> for ClientWs {
     type Result = ();
 
     fn handle(&mut self, msg: SendRelayMexRaw, ctx: &mut <Self as Actor>::Context) -> Self::Result {
+        if let Some(warning) = self.relay_drop_warning() {
+            eprintln!("{}", warning);
+            return;
+        }
         match &mut self.state {
-            ClientState::PreLogin => {},
-            ClientState::MatchMaking => {},
-            ClientState::Lobby => {},
+            ClientState::PreLogin => {
+                // A reconnection whose answer hasn't been handled yet (a login can't receive relays).
+                if self.relay_queue.len() < RELAY_QUEUE_MAX_SIZE {
+                    self.relay_queue.push(msg);
+                }
+            },
+            ClientState::MatchMaking | ClientState::Lobby => {},// Dropped above.
             ClientState::PrePlaying(_) => {
                 if self.relay_queue.len() >= RELAY_QUEUE_MAX_SIZE {
                     eprintln!("Client {} not responding to event_room_start, queue full. kicking out", self.session_id);
@@ -446,8 +1445,8 @@ impl Handler<server_actor::SendRelayMexRaw> for ClientWs {
                 }
                 self.relay_queue.push(msg)
             },
-            ClientState::Playing => {
-                ctx.text(msg.data);
+            ClientState::Playing | ClientState::Spectating => {
+                self.send_relay(ctx, msg.data);
             },
         }
     }
@@ -472,15 +1471,33 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for ClientWs {
 
         let text = match msg {
             ws::Message::Ping(msg) => {
-                self.last_hb = Instant::now();
+                self.last_hb = self.clock.now();
                 ctx.pong(&msg);
                 return
             },
-            ws::Message::Pong(_) => {
-                self.last_hb = Instant::now();
+            ws::Message::Pong(msg) => {
+                self.last_hb = self.clock.now();
+                if let Ok(bytes) = msg[..].try_into() {
+                    // Pongs can come out of order (or be made up), the estimate only moves forward.
+                    let sent = u64::from_be_bytes(bytes).min(self.sent_bytes);
+                    self.received_bytes = self.received_bytes.max(sent);
+                }
                 return
             }
-            ws::Message::Text(text) => text,
+            ws::Message::Text(text) => {
+                self.last_activity = self.clock.now();
+                text
+            },
+            ws::Message::Binary(data) => {
+                self.last_activity = self.clock.now();
+                if self.state == ClientState::Playing && self.allow_relay(ctx) {
+                    self.send_droppable(ctx, server_actor::SendRelayBinary {
+                        sender_id: self.session_id,
+                        data,
+                    });
+                }
+                return
+            },
             ws::Message::Close(_) => {
                 ctx.stop();
                 return
@@ -500,7 +1517,7 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for ClientWs {
             Ok(x) => x,
             Err(_) => {
                 let err = protocol::Error::from("Invalid Json".into(), None);
-                self.send_message(ctx, &err);
+                self.send_protocol_error(ctx, err);
                 return
             },
         };
@@ -508,21 +1525,59 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for ClientWs {
         let id = match id_message.id {
             None => {
                 let err = protocol::Error::from("Id missing".into(), None);
-                self.send_message(ctx, &err);
+                self.send_protocol_error(ctx, err);
                 return
             },
             Some(x) => x,
         };
 
+        if self.pending_login {
+            let err = protocol::Error::from_origin(id, "login_pending".into(), None);
+            self.send_error(ctx, err);
+            return
+        }
+
+        let mtype = match id_message.mtype {
+            Some(x) => x,
+            None => {
+                let err = protocol::Error::from_origin(id, "unknown_message_type".into(), Some("Type missing".into()));
+                self.send_protocol_error(ctx, err);
+                return
+            },
+        };
+
+        if !protocol::MESSAGE_TYPES.contains(&mtype.as_str()) {
+            let err = protocol::Error::from_origin(id, "unknown_message_type".into(), Some(format!("Unknown type \"{}\"", mtype).into()));
+            self.send_protocol_error(ctx, err);
+            return
+        }
+
+        // Retries of already processed requests are answered before the state check since the state has probably changed.
+        if let Some(key) = &id_message.idempotency_key {
+            if self.replay_result(ctx, id, &mtype, key) {
+                return
+            }
+        }
+
+        if !self.state.allowed_messages().contains(&mtype.as_str()) {
+            let err = protocol::Error::from_origin(
+                id, "message_not_allowed_in_state".into(),
+                Some(format!("\"{}\" is not allowed in state {}", mtype, self.state.name()).into())
+            );
+            self.send_protocol_error(ctx, err);
+            return
+        }
+
         let mex = match serde_json::from_str::<ReceivedMessage>(&text) {
             Ok(x) => x,
             Err(x) => {
                 let err = protocol::Error::from_origin(id, "Invalid Json".into(), Some(x.to_string().into()));
-                self.send_message(ctx, &err);
+                self.send_protocol_error(ctx, err);
                 return;
             }
         };
 
+        self.protocol_errors = 0;
         self.handle_message(ctx, id, mex);
     }
 }
@@ -531,6 +1586,365 @@ pub async fn matchmaking_start(
     req: HttpRequest,
     stream: web::Payload,
     data: web::Data<Addr<server_actor::ServerActor>>,
+    config: web::Data<Arc<Config>>,
 ) -> Result<HttpResponse, Error> {
-    ws::start(ClientWs::new(data.get_ref().clone()), &req, stream)
+    ws::start(ClientWs::new(data.get_ref().clone(), config.get_ref().clone()), &req, stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::clock::ManualClock;
+    use crate::test_client::{settle, Frame, TestClient};
+
+    use super::*;
+
+    #[test]
+    fn relay_bucket_drops_past_the_limit() {
+        let start = Instant::now();
+        let mut bucket = RelayBucket::new(3, start);
+        for _ in 0..3 {
+            assert_eq!(bucket.take(3, start), RelayVerdict::Allowed);
+        }
+        assert_eq!(bucket.take(3, start), RelayVerdict::Dropped { warn: true });
+        assert_eq!(bucket.take(3, start), RelayVerdict::Dropped { warn: false });
+
+        // A third of a second refills a token.
+        let later = start + Duration::from_millis(334);
+        assert_eq!(bucket.take(3, later), RelayVerdict::Allowed);
+        assert_eq!(bucket.take(3, later), RelayVerdict::Dropped { warn: false });
+
+        // Keeps flooding (10 packets every 100ms) until RELAY_FLOOD_TIME.
+        let mut now = later;
+        while now + Duration::from_millis(100) < start + RELAY_FLOOD_TIME {
+            now += Duration::from_millis(100);
+            for _ in 0..10 {
+                assert_ne!(bucket.take(3, now), RelayVerdict::Flood);
+            }
+        }
+        // The token refilled since the last tick can still be taken.
+        bucket.take(3, start + RELAY_FLOOD_TIME);
+        assert_eq!(bucket.take(3, start + RELAY_FLOOD_TIME), RelayVerdict::Flood);
+    }
+
+    #[test]
+    fn relay_bucket_without_limit() {
+        let now = Instant::now();
+        let mut bucket = RelayBucket::new(0, now);
+        for _ in 0..1000 {
+            assert_eq!(bucket.take(0, now), RelayVerdict::Allowed);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn heartbeat_times_out() {
+        let config = Arc::new(Config::default());
+        let clock = Arc::new(ManualClock::new());
+        let mut client = ClientWs::with_clock(ServerActor::default().start(), config.clone(), clock.clone());
+        let timeout = Duration::from_millis(config.client_timeout_ms);
+
+        clock.advance(timeout);
+        assert!(!client.heartbeat_expired());
+
+        // A pong resets the timeout.
+        client.last_hb = clock.now();
+        clock.advance(timeout);
+        assert!(!client.heartbeat_expired());
+
+        clock.advance(Duration::from_millis(1));
+        assert!(client.heartbeat_expired());
+    }
+
+    #[actix_rt::test]
+    async fn relays_outside_of_a_game_are_logged() {
+        let mut client = ClientWs::new(ServerActor::default().start(), Arc::new(Config::default()));
+        client.session_id = 42;
+        client.state = ClientState::Lobby;
+        let warning = client.relay_drop_warning().expect("No warning");
+        assert!(warning.contains("42") && warning.contains("Lobby"), "{}", warning);
+
+        client.state = ClientState::Playing;
+        assert_eq!(client.relay_drop_warning(), None);
+        client.state = ClientState::PreLogin;
+        client.pending_login = true;// Reconnecting, the packets are queued.
+        assert_eq!(client.relay_drop_warning(), None);
+    }
+
+    /// Skips the messages until the next ping, returns its payload.
+    async fn next_ping(client: &mut TestClient) -> Vec<u8> {
+        loop {
+            if let Frame::Ping(payload) = client.recv_frame().await {
+                return payload;
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn late_pongs_only_acknowledge_their_ping() {
+        let config = Arc::new(Config {
+            heartbeat_interval_ms: 30,
+            send_buffer_high_water: 1_000,
+            ..Config::default()
+        });
+        let db = ServerActor::new(config.clone()).start();
+        let mut client = TestClient::connect(&db, &config);
+        client.login("a").await;
+
+        let late = next_ping(&mut client).await;
+        let acked = u64::from_be_bytes(late[..].try_into().unwrap());
+        let mut sent = acked;
+        while sent < acked + 500 {
+            sent += client.call("capabilities", json!({})).await.to_string().len() as u64;
+        }
+        let ping = next_ping(&mut client).await;
+        let last = u64::from_be_bytes(ping[..].try_into().unwrap());
+        assert!(last >= acked + 500);
+
+        // Answered after the next ping has been sent, it still only covers what was sent before its own.
+        client.send_pong(&late);
+        let mut sent = last;
+        loop {
+            assert!(sent < last + 600, "The late pong has acknowledged the last ping");
+            client.request("capabilities", json!({}));
+            let frame = loop {
+                match client.recv_frame().await {
+                    Frame::Ping(_) => continue,
+                    x => break x,
+                }
+            };
+            match frame {
+                Frame::Text(text) => sent += text.len() as u64,
+                Frame::Close(code) => {
+                    assert_eq!(code, Some(1008));
+                    settle().await;
+                    assert!(client.is_closed());
+                    return;
+                },
+                x => panic!("Unexpected frame {:?}", x),
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn capabilities_follow_the_state() {
+        let config = Arc::new(Config::default());
+        let db = ServerActor::new(config.clone()).start();
+        let mut client = TestClient::connect(&db, &config);
+        let actions = |res: &Value| -> Vec<String> {
+            serde_json::from_value(res["actions"].clone()).unwrap()
+        };
+
+        client.login("a").await;
+        let res = client.call("capabilities", json!({})).await;
+        assert_eq!(res["state"], "matchmaking");
+        assert!(actions(&res).contains(&"room_create".to_string()));
+        assert!(!actions(&res).contains(&"room_start".to_string()));
+
+        client.call("room_create", json!({})).await;
+        let res = client.call("capabilities", json!({})).await;
+        assert_eq!(res["state"], "lobby");
+        for action in &["room_leave", "room_start", "change_avatar"] {
+            assert!(actions(&res).contains(&action.to_string()), "{}", action);
+        }
+        assert!(!actions(&res).contains(&"room_create".to_string()));
+    }
+
+    #[actix_rt::test]
+    async fn retried_creates_make_a_single_room() {
+        let config = Arc::new(Config::default());
+        let db = ServerActor::new(config.clone()).start();
+        let mut client = TestClient::connect(&db, &config);
+        client.login("a").await;
+
+        let id = client.request("room_create", json!({ "idempotencyKey": "k1" }));
+        let first = client.reply(id).await;
+        let id = client.request("room_create", json!({ "idempotencyKey": "k1" }));
+        let retry = client.reply(id).await;
+        assert_eq!(retry["result"], "ok", "{}", retry);
+        assert_eq!(retry["requestId"], id);
+        assert_eq!(retry["inviteId"], first["inviteId"]);
+        assert_eq!(db.send(server_actor::GetStats).await.unwrap().rooms, 1);
+    }
+
+    #[actix_rt::test]
+    async fn unknown_connection_types_are_rejected() {
+        let config = Arc::new(Config::default());
+        let db = ServerActor::new(config.clone()).start();
+        let mut client = TestClient::connect(&db, &config);
+        client.create_room("a").await;
+
+        let res = client.call("room_start", json!({ "connectionType": "carrier_pigeon" })).await;
+        assert_eq!(res["type"], "error");
+        assert_eq!(res["error"], "unsupported_connection_type");
+        // The connection is still usable.
+        let res = client.call("capabilities", json!({})).await;
+        assert_eq!(res["state"], "lobby");
+    }
+
+    #[actix_rt::test]
+    async fn relays_after_the_ack_follow_the_queued_ones() {
+        let config = Arc::new(Config {
+            min_players: 2,
+            ..Config::default()
+        });
+        let db = ServerActor::new(config.clone()).start();
+        let mut host = TestClient::connect(&db, &config);
+        let invite_id = host.create_room("host").await;
+        let mut guest = TestClient::connect(&db, &config);
+        guest.join_room("guest", &invite_id).await;
+        host.request("room_start", json!({ "connectionType": "server_broadcast" }));
+        host.ack_start().await;
+        let start = guest.recv_type("event_room_start").await;
+
+        for n in 1..=3 {
+            host.send_text(&json!({ "n": n }).to_string());
+        }
+        settle().await;
+        // The last relay reaches the guest while it drains its queue.
+        guest.request("event_room_start_ack", json!({ "requestId": start["id"] }));
+        host.send_text(&json!({ "n": 4 }).to_string());
+
+        let mut received = vec![];
+        while received.len() < 4 {
+            let mex = guest.recv().await;
+            if !mex["n"].is_null() {
+                received.push(mex["n"].as_u64().unwrap());
+            }
+        }
+        assert_eq!(received, [1, 2, 3, 4]);
+    }
+
+    #[actix_rt::test]
+    async fn relogins_in_a_room_are_refused() {
+        let config = Arc::new(Config::default());
+        let db = ServerActor::new(config.clone()).start();
+        let mut client = TestClient::connect(&db, &config);
+        client.login("a").await;
+
+        // The server has already put the player in a room when the canceled find is answered.
+        client.request("room_find", json!({}));
+        client.request("cancel_find", json!({}));
+        let res = client.call("login", json!({ "details": { "username": "b" } })).await;
+        assert_eq!(res["result"], "cannot_relogin_in_room");
+
+        // Once the room has been left it's fine.
+        settle().await;
+        client.login("b").await;
+    }
+
+    #[actix_rt::test]
+    async fn finds_join_the_available_rooms() {
+        let config = Arc::new(Config {
+            max_rooms: 1,
+            max_players: 2,
+            min_players: 2,
+            start_countdown_secs: 60,
+            ..Config::default()
+        });
+        let db = ServerActor::new(config.clone()).start();
+        let mut first = TestClient::connect(&db, &config);
+        first.login("first").await;
+        let created = first.call("room_find", json!({})).await;
+        assert_eq!(created["result"], "ok", "{}", created);
+        assert_eq!(created["justCreated"], true);
+        assert_eq!(created["players"].as_array().unwrap().len(), 1);
+        assert_eq!(first.call("capabilities", json!({})).await["state"], "lobby");
+
+        let mut second = TestClient::connect(&db, &config);
+        second.login("second").await;
+        let joined = second.call("room_find", json!({})).await;
+        assert_eq!(joined["roomId"], created["roomId"]);
+        assert_eq!(joined["justCreated"], false);
+        assert_eq!(joined["players"].as_array().unwrap().len(), 2);
+
+        let mut third = TestClient::connect(&db, &config);
+        third.login("third").await;
+        let res = third.call("room_find", json!({})).await;
+        assert_eq!(res["result"], "game_is_full");
+        assert_eq!(third.call("capabilities", json!({})).await["state"], "matchmaking");
+    }
+
+    #[actix_rt::test]
+    async fn starts_are_acked_with_the_event_id() {
+        let config = Arc::new(Config {
+            min_players: 2,
+            ..Config::default()
+        });
+        let db = ServerActor::new(config.clone()).start();
+        let mut host = TestClient::connect(&db, &config);
+        let invite_id = host.create_room("host").await;
+        let mut guest = TestClient::connect(&db, &config);
+        let joined = guest.join_room("guest", &invite_id).await;
+        host.request("room_start", json!({ "connectionType": "server_broadcast" }));
+        host.ack_start().await;
+        let start = guest.recv_type("event_room_start").await;
+
+        // The id of an unrelated response.
+        let res = guest.call("event_room_start_ack", json!({ "requestId": joined["requestId"] })).await;
+        assert_eq!(res["error"], "Invalid request_id");
+        assert_eq!(guest.call("capabilities", json!({})).await["state"], "pre_playing");
+
+        guest.request("event_room_start_ack", json!({ "requestId": start["id"] }));
+        host.send_text(&json!({ "n": 1 }).to_string());
+        assert_eq!(guest.recv().await["n"], 1);
+    }
+
+    #[actix_rt::test]
+    async fn stale_resyncs_return_the_roster() {
+        let config = Arc::new(Config::default());
+        let db = ServerActor::new(config.clone()).start();
+        let mut host = TestClient::connect(&db, &config);
+        let invite_id = host.create_room("host").await;
+        let seen = host.call("capabilities", json!({})).await["id"].clone();
+        let mut guest = TestClient::connect(&db, &config);
+        guest.join_room("guest", &invite_id).await;
+        host.recv_type("event_player_joined").await;
+
+        // The join has been missed.
+        let res = host.call("resync_request", json!({ "lastSeenId": seen })).await;
+        assert_eq!(res["upToDate"], false);
+        assert_eq!(res["state"], "lobby");
+        assert_eq!(res["players"].as_array().unwrap().len(), 2);
+
+        let res = host.call("resync_request", json!({ "lastSeenId": res["id"] })).await;
+        assert_eq!(res["upToDate"], true);
+        assert!(res["players"].is_null());
+    }
+
+    #[actix_rt::test]
+    async fn errors_are_described_in_the_login_locale() {
+        let config = Arc::new(Config::default());
+        let db = ServerActor::new(config.clone()).start();
+        let mut client = TestClient::connect(&db, &config);
+
+        let res = client.call("login", json!({ "details": { "username": "a", "color": 0x1000000, "locale": "fr" } })).await;
+        assert_eq!(res["error"], "invalid_color");
+        assert_eq!(res["errorMessage"], "La couleur doit être une valeur RVB sur 24 bits");
+
+        client.login("a").await;
+        let res = client.call("login", json!({ "details": { "username": "a", "color": 0x1000000, "locale": "fr-CA" } })).await;
+        assert_eq!(res["errorMessage"], "La couleur doit être une valeur RVB sur 24 bits");
+        let res = client.call("login", json!({ "details": { "username": "a", "color": 0x1000000 } })).await;
+        assert_eq!(res["errorMessage"], "The color must be a 24 bit RGB value");
+    }
+
+    #[actix_rt::test]
+    async fn malformed_tokens_are_rejected() {
+        let config = Arc::new(Config::default());
+        let db = ServerActor::new(config.clone()).start();
+        let mut client = TestClient::connect(&db, &config);
+
+        let res = client.call("resume", json!({ "token": "A".repeat(16 * 1024) })).await;
+        assert_eq!(res["result"], "invalid_token");
+        let res = client.call("resume", json!({ "token": "AAAAAAAAAAAAAAAAAAAA==" })).await;
+        assert_eq!(res["result"], "invalid_token");
+        let res = client.call("reconnect", json!({ "playerId": "AAAAAAAAAAE=", "roomId": "AAAAAAAAAAE=", "token": "AAAAAAAAAAAAAAAAAAAAA+" })).await;
+        assert_eq!(res["result"], "invalid_token");
+
+        // Well formed but unknown.
+        let res = client.call("resume", json!({ "token": "AAAAAAAAAAAAAAAAAAAAA-" })).await;
+        assert_eq!(res["result"], "session_not_found");
+        client.login("a").await;
+    }
 }
@@ -0,0 +1,264 @@
+//!
+//! Server configuration, read from the environment variables once at startup.
+//! Every field falls back to its default when the variable is unset or can't be parsed.
+//!
+
+use std::env;
+use std::fmt::Display;
+use std::mem;
+use std::str::FromStr;
+
+use crate::protocol::{IdType, RoomConnectionType};
+
+/// Below this the private rooms' invite ids become too easy to guess.
+const MIN_INVITE_ID_BYTES: usize = 4;
+/// Longest seat a disconnected mobile player can hold, the others would wait for it for too long.
+const MAX_MOBILE_GRACE_MS: u64 = 10 * 60 * 1000;
+/// Fields of the relayed packets used by the server, the sender id can't overwrite them.
+const RESERVED_RELAY_FIELDS: &[&str] = &["v", "ackId", "private", "timestamp", "ts"];
+
+#[derive(Clone)]
+pub struct Config {
+    /// Give a random avatar (if AVATAR_COUNT is set) and color to players that log in with the default cosmetics (AUTO_COSMETICS).
+    pub auto_cosmetics: bool,
+    /// Number of avatars known by the clients, greater avatars are rejected, 0 to accept any (AVATAR_COUNT).
+    pub avatar_count: u32,
+    /// Token required by the admin API, if empty the admin API is disabled (ADMIN_TOKEN).
+    pub admin_token: String,
+    /// Number of bytes of the private rooms' invite ids, 6 bytes give 8 characters long codes (INVITE_ID_BYTES).
+    pub invite_id_bytes: usize,
+    /// Allow players to change their cosmetics once they've joined a room (LOBBY_COSMETICS_CHANGES).
+    pub lobby_cosmetics_changes: bool,
+    /// Max number of rooms in the server, 0 means no limit (MAX_ROOMS).
+    pub max_rooms: usize,
+    /// Max number of rooms playing at the same time, 0 means no limit (MAX_GAMES).
+    pub max_games: usize,
+    /// Connection type of the games started by the countdown (DEFAULT_CONNECTION_TYPE).
+    pub default_connection_type: RoomConnectionType,
+    /// Min time between two rooms created by the same player in milliseconds, 0 to disable (ROOM_CREATE_COOLDOWN_MS).
+    pub room_create_cooldown_ms: u64,
+    /// Put public rooms back in matchmaking once their game ends (REMATCHMAKING).
+    pub rematchmaking: bool,
+    /// Add the server time (milliseconds since the unix epoch) as "timestamp" to every relayed packet (RELAY_TIMESTAMP).
+    pub relay_timestamp: bool,
+    /// Relay messages per second over which a player is reported in the logs, 0 to disable (RELAY_RATE_ALERT).
+    pub relay_rate_alert: u32,
+    /// Length of the window in which the relay rate is measured in milliseconds (RELAY_RATE_WINDOW_MS).
+    pub relay_rate_window_ms: u64,
+    /// Max cosmetics change broadcasts per second in the whole server, the exceeding ones are delayed, 0 means no limit (COSMETICS_BROADCAST_RATE).
+    pub cosmetics_broadcast_rate: u32,
+    /// Consecutive invalid messages after which a client is disconnected, 0 means no limit (MAX_PROTOCOL_ERRORS).
+    pub max_protocol_errors: u32,
+    /// How often heartbeat pings are sent to the clients in milliseconds (HEARTBEAT_INTERVAL_MS).
+    pub heartbeat_interval_ms: u64,
+    /// How long before the lack of client responses causes a disconnection in milliseconds (CLIENT_TIMEOUT_MS).
+    pub client_timeout_ms: u64,
+    /// Apply the min players per room to the starts requested by the host too, not only to the countdown (STRICT_MIN_PLAYERS).
+    pub strict_min_players: bool,
+    /// Kick the players that don't send any message for this long while in a lobby in milliseconds, 0 to disable (LOBBY_IDLE_TIMEOUT_MS).
+    pub lobby_idle_timeout_ms: u64,
+    /// Add the server time (milliseconds since the unix epoch) as "ts" to every message sent to the clients,
+    /// the relayed packets get the "timestamp" of RELAY_TIMESTAMP instead (MESSAGE_TIMESTAMPS).
+    pub message_timestamps: bool,
+    /// Estimated bytes waiting to be received by a client over which it's disconnected, 0 means no limit (SEND_BUFFER_HIGH_WATER).
+    pub send_buffer_high_water: u64,
+    /// Max number of empty public rooms kept open for the next players instead of being deleted, 0 to disable (WARM_ROOM_POOL).
+    pub warm_room_pool: usize,
+    /// Max number of messages waiting to be processed by the server actor, past this the relays are dropped (SERVER_MAILBOX_CAPACITY).
+    pub server_mailbox_capacity: usize,
+    /// Max number of players in a room (MAX_PLAYERS).
+    pub max_players: usize,
+    /// Number of players that starts the countdown of a room, at least 2 (MIN_PLAYERS).
+    pub min_players: usize,
+    /// Length of the countdown that starts the rooms that have reached the min players in seconds (START_COUNTDOWN_SECS).
+    pub start_countdown_secs: u64,
+    /// Name of the field with the sender id added to the relayed packets (RELAY_SENDER_FIELD).
+    pub relay_sender_field: String,
+    /// Open the private rooms to the matchmaking if they haven't reached the min players after this long in milliseconds, 0 to disable (PRIVATE_ROOM_AUTO_OPEN_MS).
+    pub private_room_auto_open_ms: u64,
+    /// How long the seat of a player that loses its connection during a game is kept for it to reconnect in milliseconds, 0 to disable (RECONNECT_GRACE_MS).
+    pub reconnect_grace_ms: u64,
+    /// Max number of rooms started every 100 milliseconds, the other starts wait for the next ones, 0 for no limit (MAX_ROOM_STARTS_PER_TICK).
+    pub max_room_starts_per_tick: usize,
+    /// Spectators that asked for it join the room when the game ends, if there are free seats (SPECTATORS_JOIN_AFTER_GAME).
+    pub spectators_join_after_game: bool,
+    /// Reject the host's start requests unless every player of the room is ready (REQUIRE_READY).
+    pub require_ready: bool,
+    /// How long the session of a player that loses its connection outside of a game is kept for it to resume it in milliseconds, 0 to disable (SESSION_RESUME_MS).
+    pub session_resume_ms: u64,
+    /// Max relay packets per second sent by a client (on average, bursts of a second are allowed),
+    /// the other ones are dropped, 0 for no limit (RELAY_RATE_LIMIT).
+    pub relay_rate_limit: u32,
+    /// Reject the host's start requests until every player has been in the room for this long in milliseconds, 0 to disable (MIN_LOBBY_DWELL_MS).
+    pub min_lobby_dwell_ms: u64,
+    /// Time given to the clients between the shutdown notice (on SIGTERM or SIGINT) and the server stop in milliseconds (SHUTDOWN_GRACE_MS).
+    pub shutdown_grace_ms: u64,
+    /// Players that haven't acknowledged the start of the game after this long in milliseconds are removed from the room, 0 to disable (START_ACK_TIMEOUT_MS).
+    pub start_ack_timeout_ms: u64,
+    /// Min time the session (and the seat) of a mobile player that loses its connection is kept in milliseconds, at most 10 minutes (MOBILE_GRACE_MS).
+    pub mobile_grace_ms: u64,
+    /// Max number of players of a peer to peer game, 0 for no limit (MAX_PEER_TO_PEER_PLAYERS).
+    pub max_peer_to_peer_players: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            auto_cosmetics: false,
+            avatar_count: 0,
+            admin_token: String::new(),
+            invite_id_bytes: mem::size_of::<IdType>(),
+            lobby_cosmetics_changes: true,
+            max_rooms: 0,
+            max_games: 0,
+            default_connection_type: RoomConnectionType::ServerBroadcast,
+            room_create_cooldown_ms: 0,
+            rematchmaking: false,
+            relay_timestamp: false,
+            relay_rate_alert: 0,
+            relay_rate_window_ms: 10_000,
+            cosmetics_broadcast_rate: 0,
+            max_protocol_errors: 0,
+            heartbeat_interval_ms: 5_000,
+            client_timeout_ms: 10_000,
+            strict_min_players: false,
+            lobby_idle_timeout_ms: 0,
+            message_timestamps: false,
+            send_buffer_high_water: 0,
+            warm_room_pool: 0,
+            server_mailbox_capacity: 4096,
+            max_players: 5,
+            min_players: 3,
+            start_countdown_secs: 10,
+            relay_sender_field: "sender".to_string(),
+            private_room_auto_open_ms: 0,
+            reconnect_grace_ms: 30_000,
+            max_room_starts_per_tick: 0,
+            spectators_join_after_game: false,
+            require_ready: false,
+            session_resume_ms: 10_000,
+            relay_rate_limit: 0,
+            min_lobby_dwell_ms: 0,
+            shutdown_grace_ms: 5_000,
+            start_ack_timeout_ms: 15_000,
+            mobile_grace_ms: 90_000,
+            max_peer_to_peer_players: 6,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        let def = Config::default();
+        Config {
+            auto_cosmetics: env_or("AUTO_COSMETICS", def.auto_cosmetics),
+            avatar_count: env_or("AVATAR_COUNT", def.avatar_count),
+            admin_token: env_or("ADMIN_TOKEN", def.admin_token),
+            invite_id_bytes: env_or("INVITE_ID_BYTES", def.invite_id_bytes),
+            lobby_cosmetics_changes: env_or("LOBBY_COSMETICS_CHANGES", def.lobby_cosmetics_changes),
+            max_rooms: env_or("MAX_ROOMS", def.max_rooms),
+            max_games: env_or("MAX_GAMES", def.max_games),
+            default_connection_type: env_or("DEFAULT_CONNECTION_TYPE", def.default_connection_type),
+            room_create_cooldown_ms: env_or("ROOM_CREATE_COOLDOWN_MS", def.room_create_cooldown_ms),
+            rematchmaking: env_or("REMATCHMAKING", def.rematchmaking),
+            relay_timestamp: env_or("RELAY_TIMESTAMP", def.relay_timestamp),
+            relay_rate_alert: env_or("RELAY_RATE_ALERT", def.relay_rate_alert),
+            relay_rate_window_ms: env_or("RELAY_RATE_WINDOW_MS", def.relay_rate_window_ms),
+            cosmetics_broadcast_rate: env_or("COSMETICS_BROADCAST_RATE", def.cosmetics_broadcast_rate),
+            max_protocol_errors: env_or("MAX_PROTOCOL_ERRORS", def.max_protocol_errors),
+            heartbeat_interval_ms: env_or("HEARTBEAT_INTERVAL_MS", def.heartbeat_interval_ms),
+            client_timeout_ms: env_or("CLIENT_TIMEOUT_MS", def.client_timeout_ms),
+            strict_min_players: env_or("STRICT_MIN_PLAYERS", def.strict_min_players),
+            lobby_idle_timeout_ms: env_or("LOBBY_IDLE_TIMEOUT_MS", def.lobby_idle_timeout_ms),
+            message_timestamps: env_or("MESSAGE_TIMESTAMPS", def.message_timestamps),
+            send_buffer_high_water: env_or("SEND_BUFFER_HIGH_WATER", def.send_buffer_high_water),
+            warm_room_pool: env_or("WARM_ROOM_POOL", def.warm_room_pool),
+            server_mailbox_capacity: env_or("SERVER_MAILBOX_CAPACITY", def.server_mailbox_capacity),
+            max_players: env_or("MAX_PLAYERS", def.max_players),
+            min_players: env_or("MIN_PLAYERS", def.min_players),
+            start_countdown_secs: env_or("START_COUNTDOWN_SECS", def.start_countdown_secs),
+            relay_sender_field: env_or("RELAY_SENDER_FIELD", def.relay_sender_field),
+            private_room_auto_open_ms: env_or("PRIVATE_ROOM_AUTO_OPEN_MS", def.private_room_auto_open_ms),
+            reconnect_grace_ms: env_or("RECONNECT_GRACE_MS", def.reconnect_grace_ms),
+            max_room_starts_per_tick: env_or("MAX_ROOM_STARTS_PER_TICK", def.max_room_starts_per_tick),
+            spectators_join_after_game: env_or("SPECTATORS_JOIN_AFTER_GAME", def.spectators_join_after_game),
+            require_ready: env_or("REQUIRE_READY", def.require_ready),
+            session_resume_ms: env_or("SESSION_RESUME_MS", def.session_resume_ms),
+            relay_rate_limit: env_or("RELAY_RATE_LIMIT", def.relay_rate_limit),
+            min_lobby_dwell_ms: env_or("MIN_LOBBY_DWELL_MS", def.min_lobby_dwell_ms),
+            shutdown_grace_ms: env_or("SHUTDOWN_GRACE_MS", def.shutdown_grace_ms),
+            start_ack_timeout_ms: env_or("START_ACK_TIMEOUT_MS", def.start_ack_timeout_ms),
+            mobile_grace_ms: env_or("MOBILE_GRACE_MS", def.mobile_grace_ms),
+            max_peer_to_peer_players: env_or("MAX_PEER_TO_PEER_PLAYERS", def.max_peer_to_peer_players),
+        }
+    }
+
+    /// Connection types a room of `player_count` players can be started with.
+    pub fn connection_types_for(&self, player_count: usize) -> Vec<RoomConnectionType> {
+        let max_peers = self.max_peer_to_peer_players;
+        RoomConnectionType::SUPPORTED.iter()
+            .copied()
+            .filter(|x| *x != RoomConnectionType::PeerToPeer || max_peers == 0 || player_count <= max_peers)
+            .collect()
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let max_invite_id_bytes = mem::size_of::<IdType>();
+        if self.invite_id_bytes < MIN_INVITE_ID_BYTES || self.invite_id_bytes > max_invite_id_bytes {
+            return Err(format!("INVITE_ID_BYTES must be between {} and {}", MIN_INVITE_ID_BYTES, max_invite_id_bytes));
+        }
+        if self.min_players < 2 || self.min_players > self.max_players {
+            return Err(format!("MIN_PLAYERS must be between 2 and MAX_PLAYERS ({})", self.max_players));
+        }
+        if self.relay_sender_field.is_empty() {
+            return Err("RELAY_SENDER_FIELD must not be empty".into());
+        }
+        if RESERVED_RELAY_FIELDS.contains(&self.relay_sender_field.as_str()) {
+            return Err(format!("RELAY_SENDER_FIELD can't be one of {}", RESERVED_RELAY_FIELDS.join(", ")));
+        }
+        if self.heartbeat_interval_ms == 0 {
+            return Err("HEARTBEAT_INTERVAL_MS must be greater than 0".into());
+        }
+        if self.server_mailbox_capacity == 0 {
+            return Err("SERVER_MAILBOX_CAPACITY must be greater than 0".into());
+        }
+        if self.relay_rate_window_ms == 0 {
+            return Err("RELAY_RATE_WINDOW_MS must be greater than 0".into());
+        }
+        if self.mobile_grace_ms > MAX_MOBILE_GRACE_MS {
+            return Err(format!("MOBILE_GRACE_MS must be at most {}", MAX_MOBILE_GRACE_MS));
+        }
+        Ok(())
+    }
+}
+
+fn env_or<T: FromStr + Display>(name: &str, default: T) -> T {
+    let value = match env::var(name) {
+        Ok(x) => x,
+        Err(_) => return default,
+    };
+    match value.parse() {
+        Ok(x) => x,
+        Err(_) => {
+            eprintln!("Invalid value for {}: \"{}\", using the default ({})", name, value, default);
+            default
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relay_sender_field_is_not_reserved() {
+        let config = |field: &str| Config {
+            relay_sender_field: field.to_string(),
+            ..Config::default()
+        };
+        assert!(config("from").validate().is_ok());
+        for field in &["", "v", "ackId", "private", "timestamp", "ts"] {
+            assert!(config(field).validate().is_err(), "{}", field);
+        }
+    }
+}
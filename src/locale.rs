@@ -0,0 +1,56 @@
+//!
+//! Human readable texts sent to the clients in their language, the error codes never change.
+//! Unknown languages and missing translations fall back to English.
+//!
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize, Serialize, Copy, Clone, Debug, PartialEq, Eq, Hash, Default)]
+#[serde(from = "String", rename_all = "lowercase")]
+pub enum Locale {
+    #[default]
+    En,
+    Fr,
+}
+
+impl From<String> for Locale {
+    /// Only the language is considered ("fr-CA" is French).
+    fn from(value: String) -> Self {
+        let lang = value.split(['-', '_']).next().unwrap_or("");
+        match lang.to_ascii_lowercase().as_str() {
+            "fr" => Locale::Fr,
+            _ => Locale::En,
+        }
+    }
+}
+
+impl Locale {
+    /// Description of an error code, None if the code has no fixed description.
+    pub fn error_message(self, code: &str) -> Option<&'static str> {
+        let (en, fr) = match code {
+            "login_pending" => ("The login is still being processed", "La connexion est encore en cours"),
+            "find_pending" => ("A room search is in progress", "Une recherche de salle est en cours"),
+            "cosmetics_locked" => ("Cosmetics can't be changed once in a room", "Les cosmétiques ne peuvent pas être changés dans une salle"),
+            "invalid_avatar" => ("This avatar doesn't exist", "Cet avatar n'existe pas"),
+            "invalid_color" => ("The color must be a 24 bit RGB value", "La couleur doit être une valeur RVB sur 24 bits"),
+            "unsupported_connection_type" => ("Unsupported connection type", "Type de connexion non pris en charge"),
+            "relay_disabled" => ("The server doesn't relay peer to peer games", "Le serveur ne relaie pas les parties en pair à pair"),
+            "not_in_game" => ("You are not in a game", "Vous n'êtes pas dans une partie"),
+            "not_your_turn" => ("It's not your turn", "Ce n'est pas votre tour"),
+            "not_host" => ("Only the host can do this", "Seul l'hôte peut faire cela"),
+            "game_paused" => ("The game is paused", "La partie est en pause"),
+            "invalid_relay_json" => ("Relay packets must be json objects", "Les paquets relayés doivent être des objets json"),
+            "unsupported_relay_version" => ("Unsupported relay version", "Version de relais non prise en charge"),
+            "empty_chat_message" => ("The message is empty", "Le message est vide"),
+            "chat_message_too_long" => ("The message is too long", "Le message est trop long"),
+            "invalid_chat_message" => ("The message contains invalid characters", "Le message contient des caractères invalides"),
+            "relay_rate_limited" => ("Too many packets, some have been dropped", "Trop de paquets, certains ont été ignorés"),
+            "server_busy" => ("The server is overloaded, some messages have been dropped", "Le serveur est surchargé, des messages ont été perdus"),
+            _ => return None,
+        };
+        Some(match self {
+            Locale::En => en,
+            Locale::Fr => fr,
+        })
+    }
+}
@@ -3,32 +3,81 @@
 static GLOBAL: jemallocator::Jemalloc = jemallocator::Jemalloc;
 
 
+use std::sync::Arc;
+use std::time::Duration;
+
 use actix::prelude::*;
 use actix_web::{HttpServer, App, web};
+use actix_web::dev::Server;
 use env_logger;
 
 
+mod api_service;
 mod client_ws;
+mod clock;
+mod config;
+mod locale;
 mod protocol;
 mod server_actor;
+#[cfg(test)]
+mod test_client;
 
 
 #[actix_rt::main]
 async fn main() -> std::io::Result<()> {
     env_logger::init();
 
-    let room_db = server_actor::ServerActor::default().start();
+    let config = config::Config::from_env();
+    if let Err(err) = config.validate() {
+        eprintln!("Invalid configuration: {}", err);
+        std::process::exit(1);
+    }
+    let config = Arc::new(config);
+    let room_db = server_actor::ServerActor::new(config.clone()).start();
+    let shutdown_grace = Duration::from_millis(config.shutdown_grace_ms);
+    let db = room_db.clone();
 
     let bind_addr = std::env::var("BIND_ADDR")
         .unwrap_or_else(|_| "0.0.0.0:8081".to_string());
 
     println!("Starting server on {}", bind_addr);
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         App::new()
             .data(room_db.clone())
+            .data(config.clone())
             .route("/", web::get().to(client_ws::matchmaking_start))
+            .configure(api_service::config)
     })
         .bind(bind_addr)?
-        .run()
-        .await
+        .disable_signals()// The clients are told before stopping, see shutdown.
+        .run();
+
+    let (srv, addr) = (server.clone(), db.clone());
+    actix_rt::spawn(async move {
+        if actix_rt::signal::ctrl_c().await.is_ok() {
+            shutdown(srv, addr, "stop", shutdown_grace).await;
+        }
+    });
+    #[cfg(unix)]
+    {
+        use actix_rt::signal::unix::{signal, SignalKind};
+        let mut sigterm = signal(SignalKind::terminate())?;
+        let srv = server.clone();
+        actix_rt::spawn(async move {
+            if sigterm.recv().await.is_some() {
+                shutdown(srv, db, "restart", shutdown_grace).await;
+            }
+        });
+    }
+
+    server.await
+}
+
+/// Tells the clients that the server is going away and stops it after the grace time,
+/// the HTTP server keeps running in the meantime.
+async fn shutdown(server: Server, db: Addr<server_actor::ServerActor>, reason: &'static str, grace: Duration) {
+    println!("Shutting down in {}ms ({})", grace.as_millis(), reason);
+    let _ = db.send(server_actor::Shutdown { reason }).await;
+    actix_rt::time::delay_for(grace).await;
+    server.stop(true).await;
 }
@@ -1,5 +1,6 @@
 use std::fmt;
 use std::mem;
+use std::str::FromStr;
 
 use ::serde::{Deserialize, Serialize};
 use serde::{Deserializer, Serializer};
@@ -8,6 +9,8 @@ use serde::de::Visitor;
 use serde::export::Formatter;
 use std::borrow::Cow;
 
+use crate::locale::Locale;
+
 pub type IdType = usize;
 
 // Common data
@@ -20,52 +23,213 @@ pub struct PlayerObject {
     #[serde(flatten)]
     pub cosmetics: PlayerCosmetics,
     pub is_host: bool,
+    /// Still playing the room's game (ex. the others are back in the lobby after it).
+    pub in_game: bool,
 }
 
 #[derive(Deserialize, Serialize, Clone, PartialEq, Eq)]
 pub struct LoginData {
     pub username: String,
-    #[serde(flatten)]
+    /// The missing fields get their default value, so a login can just contain the username.
+    #[serde(flatten, deserialize_with = "deserialize_login_cosmetics")]
     pub cosmetics: PlayerCosmetics,
+    /// Receive roster changes as event_roster_diff instead of the single player events.
+    #[serde(default, rename = "rosterDiffs")]
+    pub roster_diffs: bool,
+    /// Language of the human readable texts (ex. "fr"), English if unknown.
+    #[serde(default)]
+    pub locale: Locale,
+    #[serde(default)]
+    pub platform: Platform,
 }
 
-#[derive(Deserialize, Serialize, Clone, PartialEq, Eq)]
+/// Kind of device of the client, mobile clients lose their connection whenever they're backgrounded.
+#[derive(Deserialize, Serialize, Copy, Clone, Debug, PartialEq, Eq, Default)]
+#[serde(from = "String", rename_all = "lowercase")]
+pub enum Platform {
+    #[default]
+    Desktop,
+    Mobile,
+}
+
+impl From<String> for Platform {
+    /// Unknown platforms are treated like desktops.
+    fn from(value: String) -> Self {
+        match value.to_ascii_lowercase().as_str() {
+            "mobile" => Platform::Mobile,
+            _ => Platform::Desktop,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq, Default)]
 pub struct PlayerCosmetics {
     pub avatar: u32,
     pub color: u64,
 }
 
+/// Only the login accepts partial cosmetics, a change_avatar must contain both fields.
+fn deserialize_login_cosmetics<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PlayerCosmetics, D::Error> {
+    #[derive(Deserialize, Default)]
+    #[serde(default)]
+    struct LoginCosmetics {
+        avatar: u32,
+        color: u64,
+    }
+
+    let cosmetics = LoginCosmetics::deserialize(deserializer)?;
+    Ok(PlayerCosmetics {
+        avatar: cosmetics.avatar,
+        color: cosmetics.color,
+    })
+}
+
+/// Colors are 24 bit RGB values (0xRRGGBB), alpha is not supported.
+pub const MAX_COLOR: u64 = 0xFFFFFF;
+
+impl PlayerCosmetics {
+    /// Returns the error code of the first invalid field, if any.
+    /// Avatars must be below `avatar_count` (0 accepts any avatar).
+    pub fn validate(&self, avatar_count: u32) -> Result<(), &'static str> {
+        if avatar_count > 0 && self.avatar >= avatar_count {
+            return Err("invalid_avatar");
+        }
+        if self.color > MAX_COLOR {
+            return Err("invalid_color");
+        }
+        Ok(())
+    }
+}
+
+/// Reconnect tokens are 16 random bytes encoded in URL-safe base64 without padding.
+pub const RECONNECT_TOKEN_LEN: usize = 22;
+
+/// Checks the format of a reconnect token sent by a client, so that the malformed ones are rejected without a lookup.
+pub fn is_valid_reconnect_token(token: &str) -> bool {
+    token.len() == RECONNECT_TOKEN_LEN && token.bytes().all(|x| x.is_ascii_alphanumeric() || x == b'-' || x == b'_')
+}
+
+/// Max length of a chat message, in characters.
+pub const MAX_CHAT_LEN: usize = 256;
+
+/// Returns the chat text without the surrounding whitespace, or the error code if it can't be sent.
+pub fn validate_chat_text(text: &str) -> Result<&str, &'static str> {
+    let text = text.trim();
+    if text.is_empty() {
+        return Err("empty_chat_message");
+    }
+    if text.chars().count() > MAX_CHAT_LEN {
+        return Err("chat_message_too_long");
+    }
+    if text.chars().any(char::is_control) {
+        return Err("invalid_chat_message");
+    }
+    Ok(text)
+}
+
 // Client to Server data
 
 #[derive(Deserialize)]
 pub struct IdMessage {
     pub id: Option<u64>,
+    #[serde(rename = "type")]
+    pub mtype: Option<String>,
+    #[serde(rename = "idempotencyKey")]
+    pub idempotency_key: Option<String>,
 }
 
+/// The "type" of every ReceivedMessage variant, keep it in sync with the enum.
+pub const MESSAGE_TYPES: &[&str] = &[
+    "login",
+    "reconnect",
+    "resume",
+    "change_avatar",
+    "room_find",
+    "cancel_find",
+    "room_create",
+    "room_leave",
+    "room_join",
+    "room_peek",
+    "room_rejoin",
+    "room_start",
+    "kick_player",
+    "chat_send",
+    "set_ready",
+    "resync_request",
+    "spectate_join",
+    "capabilities",
+    "event_room_start_ack",
+];
+
 #[derive(Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum ReceivedMessage {
     Login {
         details: LoginData
     },
+    #[serde(rename_all = "camelCase")]
+    Reconnect {
+        player_id: SerId,
+        room_id: SerId,
+        token: String,
+    },
+    Resume {
+        token: String,
+    },
     ChangeAvatar {
         #[serde(flatten)]
         cosmetics: PlayerCosmetics,
     },
     RoomFind {  
     },
+    CancelFind {
+    },
+    #[serde(rename_all = "camelCase")]
     RoomCreate {
+        #[serde(default)]
+        idempotency_key: Option<String>,
     },
     RoomLeave {
     },
     #[serde(rename_all = "camelCase")]
     RoomJoin {
-        invite_id: SerId,
+        invite_id: String,// Parsed by the handler to answer with a specific error.
+        #[serde(default)]
+        idempotency_key: Option<String>,
+    },
+    RoomRejoin {
+    },
+    #[serde(rename_all = "camelCase")]
+    RoomPeek {
+        invite_id: String,
     },
     #[serde(rename_all = "camelCase")]
     RoomStart {
         connection_type: RoomConnectionType,
     },
+    KickPlayer {
+        player: SerId,
+    },
+    ChatSend {
+        text: String,
+    },
+    SetReady {
+        ready: bool,
+    },
+    Capabilities {
+    },
+    #[serde(rename_all = "camelCase")]
+    SpectateJoin {
+        room_id: SerId,
+        /// Join the room when the game ends, if enabled in the config.
+        #[serde(default)]
+        join_next: bool,
+    },
+    #[serde(rename_all = "camelCase")]
+    ResyncRequest {
+        #[serde(default)]
+        last_seen_id: Option<u64>,
+    },
     #[serde(rename_all = "camelCase")]
     EventRoomStartAck {
         request_id: u64,
@@ -76,7 +240,69 @@ pub enum ReceivedMessage {
 #[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Eq)]
 #[serde(rename_all = "snake_case")]
 pub enum RoomConnectionType {
+    /// Every packet is relayed by the server to the whole room, identified by the broadcast id.
     ServerBroadcast,
+    /// The players connect to each other, the server only assigns them a peer index and doesn't relay anything.
+    PeerToPeer,
+    /// The server relays the packets like ServerBroadcast, the players only know each other by their ids.
+    Relay,
+    /// Any type this server doesn't know (ex. sent by newer clients), rejected when the room is started.
+    #[serde(other, skip_serializing)]
+    Unsupported,
+}
+
+impl RoomConnectionType {
+    /// Every type supported by the server.
+    pub const SUPPORTED: &'static [RoomConnectionType] = &[
+        RoomConnectionType::ServerBroadcast,
+        RoomConnectionType::PeerToPeer,
+        RoomConnectionType::Relay,
+    ];
+
+    /// The server relays the game packets (and keeps track of the turns).
+    pub fn is_relayed(self) -> bool {
+        match self {
+            RoomConnectionType::ServerBroadcast | RoomConnectionType::Relay => true,
+            RoomConnectionType::PeerToPeer | RoomConnectionType::Unsupported => false,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            RoomConnectionType::ServerBroadcast => "server_broadcast",
+            RoomConnectionType::PeerToPeer => "peer_to_peer",
+            RoomConnectionType::Relay => "relay",
+            RoomConnectionType::Unsupported => "unsupported",
+        }
+    }
+}
+
+impl fmt::Display for RoomConnectionType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for RoomConnectionType {
+    type Err = ();
+
+    /// Only the supported types are accepted.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "server_broadcast" => Ok(RoomConnectionType::ServerBroadcast),
+            "peer_to_peer" => Ok(RoomConnectionType::PeerToPeer),
+            "relay" => Ok(RoomConnectionType::Relay),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Connection hint of a peer to peer game.
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PeerInfo {
+    pub player: SerId,
+    pub peer_index: u32,
 }
 
 
@@ -84,6 +310,8 @@ pub enum RoomConnectionType {
 #[derive(Serialize)]
 pub struct OutMessage<T: Serialize> {
     pub id: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ts: Option<u64>,
     #[serde(flatten)]
     pub mex: T,
 }
@@ -132,6 +360,9 @@ pub enum OutEvent {
         #[serde(skip_serializing_if = "Option::is_none")]
         new_host: Option<SerId>,
     },
+    EventPlayerReturned {
+        player: SerId,
+    },
     EventPlayerAvatarChange {
         player: SerId,
         #[serde(flatten)]
@@ -140,14 +371,73 @@ pub enum OutEvent {
     #[serde(rename_all = "camelCase")]
     EventRoomStart {
         connection_type: RoomConnectionType,
-        broadcast_id: String,
-    }
+        #[serde(skip_serializing_if = "Option::is_none")]
+        broadcast_id: Option<SerId>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        peers: Option<Vec<PeerInfo>>,
+        turn_order: Vec<SerId>,
+    },
+    EventRoomStartFailed {
+        reason: &'static str,
+    },
+    EventAnnouncement {
+        text: String,
+    },
+    EventRosterDiff {
+        added: Vec<PlayerObject>,
+        removed: Vec<SerId>,
+        changed: Vec<PlayerObject>,
+    },
+    EventServerShutdown {
+        reason: &'static str,
+    },
+    EventKicked {
+        reason: &'static str,
+    },
+    EventHostAssigned {},
+    EventRoomVisibilityChanged {
+        public: bool,
+    },
+    EventChat {
+        player: SerId,
+        text: String,
+    },
+    /// The last messages of the room's chat, sent to the players that join it.
+    EventChatHistory {
+        messages: Vec<ChatEntry>,
+    },
+    EventPlayerReady {
+        player: SerId,
+        ready: bool,
+    },
+    EventSpectateEnded {
+        /// Roster of the room if the spectator has joined it.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        players: Option<Vec<PlayerObject>>,
+    },
+}
+
+#[derive(Serialize, Clone)]
+pub struct ChatEntry {
+    pub player: SerId,
+    pub text: String,
 }
 
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct LoginResponse {
     pub player_id: SerId,
+    pub reconnect_token: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ResumeResponse {
+    pub player_id: SerId,
+    pub state: &'static str,
+    pub player: PlayerObject,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub players: Option<Vec<PlayerObject>>,
 }
 
 #[derive(Serialize)]
@@ -162,7 +452,7 @@ pub struct RoomFindResponse {
 #[serde(rename_all = "camelCase")]
 pub struct RoomCreateResponse {
     pub players: [PlayerObject; 1],
-    pub invite_id: SerId,
+    pub invite_id: InviteId,
 }
 
 #[derive(Serialize)]
@@ -171,6 +461,36 @@ pub struct RoomJoinResponse {
     pub players: Vec<PlayerObject>,
 }
 
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomPeekResponse {
+    pub players: Vec<PlayerObject>,
+    pub player_count: usize,
+    pub max_players: usize,
+    pub joinable: bool,
+    pub connection_types: Vec<RoomConnectionType>,// The ones the room can be started with, at its current size.
+}
+
+#[derive(Serialize)]
+pub struct ChangeAvatarResponse {
+    pub changed: bool,// False if the cosmetics were already the same, nobody else has been told.
+}
+
+#[derive(Serialize)]
+pub struct CapabilitiesResponse {
+    pub state: &'static str,
+    pub actions: &'static [&'static str],
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ResyncResponse {
+    pub state: &'static str,
+    pub up_to_date: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub players: Option<Vec<PlayerObject>>,
+}
+
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Error<'a, 'b> {
@@ -270,6 +590,156 @@ impl From<SerId> for IdType {
     }
 }
 
+/// Room id as shown to the players, only the lowest `len` bytes are encoded to keep the invite codes short.
+/// When decoding every length up to the full id is accepted (so full room ids are valid invite ids too).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct InviteId {
+    pub id: IdType,
+    pub len: usize,
+}
+
+impl InviteId {
+    pub fn parse(v: &str) -> Result<InviteId, &'static str> {
+        let data = match base64::decode(v) {
+            Ok(x) => x,
+            Err(_) => return Err("Invalid ID"),
+        };
+
+        if data.is_empty() || data.len() > mem::size_of::<IdType>() {
+            return Err("Invalid ID length");
+        }
+        let mut u64_data = [0; mem::size_of::<IdType>()];
+        u64_data[mem::size_of::<IdType>() - data.len()..].copy_from_slice(&data);
+        Ok(InviteId {
+            id: IdType::from_be_bytes(u64_data),
+            len: data.len(),
+        })
+    }
+}
+
+impl Serialize for InviteId {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: Serializer,
+    {
+        let data: [u8; mem::size_of::<IdType>()] = self.id.to_be_bytes();
+        let str = base64::encode(&data[data.len() - self.len..]);
+        serializer.serialize_str(&str)
+    }
+}
+
+struct InviteIdVisitor;
+
+impl<'de> Visitor<'de> for InviteIdVisitor {
+    type Value = InviteId;
+
+    fn expecting(&self, formatter: &mut Formatter) -> fmt::Result {
+        formatter.write_str("an invite ID")
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+        where
+            E: de::Error {
+        InviteId::parse(v).map_err(E::custom)
+    }
+}
+
+impl<'de> Deserialize<'de> for InviteId {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: Deserializer<'de> {
+        deserializer.deserialize_str(InviteIdVisitor)
+    }
+}
+
+
+/// --------------------- HTTP API ---------------------
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerCapacity {
+    pub players: usize,
+    pub rooms: usize,
+    pub max_rooms: Option<usize>,// None if there's no limit.
+    pub max_players: Option<usize>,// Seats of MAX_ROOMS full rooms, None if there's no limit.
+    pub accepting_new: bool,
+}
+
+/// Answer of /session/status, `state` and `roomId` are only present if the session still exists.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionStatus {
+    pub status: &'static str,// "live", "grace" (disconnected but still kept) or "expired"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub room_id: Option<SerId>,// Its room, or the watched one.
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerStats {
+    pub players: usize,
+    pub rooms: usize,
+    pub available_rooms: usize,// Public rooms that the matchmaking can fill.
+    pub playing_rooms: usize,
+    pub matchmaking_rooms: usize,
+}
+
+/// --------------------- ADMIN API ---------------------
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AdminPlayerInfo {
+    pub id: SerId,
+    pub username: String,
+    pub room: Option<SerId>,
+    pub in_game: bool,
+    pub buffered_bytes: u64,
+    pub idle_secs: u64,
+}
+
+#[derive(Serialize, Clone)]
+pub struct RoomLogEntry {
+    pub time: u64,// Milliseconds since the unix epoch.
+    pub event: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AdminRoomDump {
+    pub id: SerId,
+    pub state: &'static str,
+    pub public: bool,
+    pub persistent: bool,
+    pub players: Vec<AdminPlayerInfo>,
+    pub relay_count: u64,
+    pub log: Vec<RoomLogEntry>,
+}
+
+/// Summary of a game, recorded when the first player goes back to the lobby.
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct CompletedGame {
+    pub room_id: SerId,
+    pub players: Vec<SerId>,// The ones the game started with.
+    pub connection_type: RoomConnectionType,
+    pub duration_ms: u64,
+    pub ended_at: u64,// Milliseconds since the unix epoch.
+}
+
+#[derive(Serialize)]
+pub struct CompletedGameList {
+    pub games: Vec<CompletedGame>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AdminPlayerList {
+    pub total: usize,
+    pub page: usize,
+    pub players: Vec<AdminPlayerInfo>,
+}
+
 
 /// --------------------- IN-GAME PROTOCOL ---------------------
 
@@ -277,6 +747,14 @@ impl From<SerId> for IdType {
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum ReceivedGameMessage {
     EndGame {},
+    EndTurn {},
+    Pause {},
+    Resume {},
+    #[serde(rename_all = "camelCase")]
+    RelayAck {
+        sender: SerId,
+        ack_id: u64,
+    },
 }
 
 #[derive(Serialize)]
@@ -296,4 +774,250 @@ pub enum OutGameEvent {
         #[serde(skip_serializing_if = "Option::is_none")]
         new_host: Option<SerId>,
     },
+    /// The player has lost its connection, it keeps its seat while waiting for it to reconnect.
+    PlayerDisconnected {
+        player: SerId,
+    },
+    PlayerReconnected {
+        player: SerId,
+    },
+    #[serde(rename_all = "camelCase")]
+    TurnStarted {
+        player: SerId,
+        turn_number: u32,
+    },
+    GamePaused {
+        by: SerId,
+    },
+    GameResumed {
+        by: SerId,
+    },
+    #[serde(rename_all = "camelCase")]
+    RelayDelivered {
+        message_id: u64,
+        acked: Vec<SerId>,
+        missing: Vec<SerId>,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    fn id(x: IdType) -> String {
+        SerId(x).to_string()
+    }
+
+    fn player(x: IdType) -> PlayerObject {
+        PlayerObject {
+            id: SerId(x),
+            username: format!("player{}", x),
+            cosmetics: PlayerCosmetics { avatar: 2, color: 0xFF0000 },
+            is_host: x == 1,
+            in_game: false,
+        }
+    }
+
+    fn player_json(x: IdType) -> Value {
+        json!({
+            "id": id(x),
+            "username": format!("player{}", x),
+            "avatar": 2,
+            "color": 0xFF0000,
+            "isHost": x == 1,
+            "inGame": false,
+        })
+    }
+
+    fn assert_json<T: Serialize>(value: &T, expected: Value) {
+        assert_eq!(serde_json::to_value(value).unwrap(), expected);
+    }
+
+    #[test]
+    fn cosmetics_are_validated() {
+        let cosmetics = |avatar, color| PlayerCosmetics { avatar, color };
+        assert_eq!(cosmetics(0, 0).validate(4), Ok(()));
+        assert_eq!(cosmetics(3, MAX_COLOR).validate(4), Ok(()));
+        assert_eq!(cosmetics(4, 0).validate(4), Err("invalid_avatar"));
+        assert_eq!(cosmetics(1000, 0).validate(0), Ok(()));// No avatar count, any avatar.
+        assert_eq!(cosmetics(0, MAX_COLOR + 1).validate(4), Err("invalid_color"));
+        assert_eq!(cosmetics(4, MAX_COLOR + 1).validate(4), Err("invalid_avatar"));
+    }
+
+    #[test]
+    fn out_events_wire_format() {
+        assert_json(&OutEvent::EventPlayerJoined { player: player(1) }, json!({
+            "type": "event_player_joined",
+            "player": player_json(1),
+        }));
+        assert_json(&OutEvent::EventPlayerLeft { player: SerId(2), new_host: None }, json!({
+            "type": "event_player_left",
+            "player": id(2),
+        }));
+        assert_json(&OutEvent::EventPlayerLeft { player: SerId(1), new_host: Some(SerId(2)) }, json!({
+            "type": "event_player_left",
+            "player": id(1),
+            "newHost": id(2),
+        }));
+        assert_json(&OutEvent::EventPlayerAvatarChange {
+            player: SerId(1),
+            cosmetics: PlayerCosmetics { avatar: 3, color: 0x00FF00 },
+        }, json!({
+            "type": "event_player_avatar_change",
+            "player": id(1),
+            "avatar": 3,
+            "color": 0x00FF00,
+        }));
+        assert_json(&OutEvent::EventRoomStart {
+            connection_type: RoomConnectionType::ServerBroadcast,
+            broadcast_id: Some(SerId(7)),
+            peers: None,
+            turn_order: vec![SerId(1), SerId(2)],
+        }, json!({
+            "type": "event_room_start",
+            "connectionType": "server_broadcast",
+            "broadcastId": id(7),
+            "turnOrder": [id(1), id(2)],
+        }));
+        assert_json(&OutEvent::EventRoomStart {
+            connection_type: RoomConnectionType::PeerToPeer,
+            broadcast_id: None,
+            peers: Some(vec![PeerInfo { player: SerId(1), peer_index: 0 }]),
+            turn_order: vec![SerId(1)],
+        }, json!({
+            "type": "event_room_start",
+            "connectionType": "peer_to_peer",
+            "peers": [{"player": id(1), "peerIndex": 0}],
+            "turnOrder": [id(1)],
+        }));
+        assert_json(&OutEvent::EventRoomStart {
+            connection_type: RoomConnectionType::Relay,
+            broadcast_id: None,
+            peers: None,
+            turn_order: vec![],
+        }, json!({
+            "type": "event_room_start",
+            "connectionType": "relay",
+            "turnOrder": [],
+        }));
+        assert_json(&OutEvent::EventRoomStartFailed { reason: "not_ready" }, json!({
+            "type": "event_room_start_failed",
+            "reason": "not_ready",
+        }));
+        assert_json(&OutEvent::EventAnnouncement { text: "hi".into() }, json!({
+            "type": "event_announcement",
+            "text": "hi",
+        }));
+        assert_json(&OutEvent::EventRosterDiff {
+            added: vec![player(1)],
+            removed: vec![SerId(2)],
+            changed: vec![],
+        }, json!({
+            "type": "event_roster_diff",
+            "added": [player_json(1)],
+            "removed": [id(2)],
+            "changed": [],
+        }));
+        assert_json(&OutEvent::EventServerShutdown { reason: "restart" }, json!({
+            "type": "event_server_shutdown",
+            "reason": "restart",
+        }));
+        assert_json(&OutEvent::EventKicked { reason: "host" }, json!({
+            "type": "event_kicked",
+            "reason": "host",
+        }));
+        assert_json(&OutEvent::EventHostAssigned {}, json!({
+            "type": "event_host_assigned",
+        }));
+        assert_json(&OutEvent::EventRoomVisibilityChanged { public: true }, json!({
+            "type": "event_room_visibility_changed",
+            "public": true,
+        }));
+        assert_json(&OutEvent::EventChat { player: SerId(1), text: "gg".into() }, json!({
+            "type": "event_chat",
+            "player": id(1),
+            "text": "gg",
+        }));
+        assert_json(&OutEvent::EventChatHistory { messages: vec![ChatEntry { player: SerId(1), text: "gg".into() }] }, json!({
+            "type": "event_chat_history",
+            "messages": [{ "player": id(1), "text": "gg" }],
+        }));
+        assert_json(&OutEvent::EventPlayerReady { player: SerId(1), ready: true }, json!({
+            "type": "event_player_ready",
+            "player": id(1),
+            "ready": true,
+        }));
+        assert_json(&OutEvent::EventSpectateEnded { players: None }, json!({
+            "type": "event_spectate_ended",
+        }));
+        assert_json(&OutEvent::EventSpectateEnded { players: Some(vec![player(2)]) }, json!({
+            "type": "event_spectate_ended",
+            "players": [player_json(2)],
+        }));
+    }
+
+    #[test]
+    fn out_game_events_wire_format() {
+        assert_json(&OutGameEvent::PlayerLeft { player: SerId(2), new_host: None }, json!({
+            "type": "player_left",
+            "player": id(2),
+        }));
+        assert_json(&OutGameEvent::PlayerLeft { player: SerId(1), new_host: Some(SerId(2)) }, json!({
+            "type": "player_left",
+            "player": id(1),
+            "newHost": id(2),
+        }));
+        assert_json(&OutGameEvent::PlayerDisconnected { player: SerId(2) }, json!({
+            "type": "player_disconnected",
+            "player": id(2),
+        }));
+        assert_json(&OutGameEvent::PlayerReconnected { player: SerId(2) }, json!({
+            "type": "player_reconnected",
+            "player": id(2),
+        }));
+        assert_json(&OutGameEvent::TurnStarted { player: SerId(1), turn_number: 3 }, json!({
+            "type": "turn_started",
+            "player": id(1),
+            "turnNumber": 3,
+        }));
+        assert_json(&OutGameEvent::GamePaused { by: SerId(1) }, json!({
+            "type": "game_paused",
+            "by": id(1),
+        }));
+        assert_json(&OutGameEvent::GameResumed { by: SerId(1) }, json!({
+            "type": "game_resumed",
+            "by": id(1),
+        }));
+        assert_json(&OutGameEvent::RelayDelivered {
+            message_id: 4,
+            acked: vec![SerId(1)],
+            missing: vec![SerId(2)],
+        }, json!({
+            "type": "relay_delivered",
+            "messageId": 4,
+            "acked": [id(1)],
+            "missing": [id(2)],
+        }));
+    }
+
+    #[test]
+    fn login_cosmetics_default_to_zero() {
+        let mex: ReceivedMessage = serde_json::from_str(r#"{"type": "login", "details": {"username": "a"}}"#).unwrap();
+        match mex {
+            ReceivedMessage::Login { details } => assert!(details.cosmetics == PlayerCosmetics { avatar: 0, color: 0 }),
+            _ => panic!("Not a login"),
+        }
+    }
+
+    #[test]
+    fn change_avatar_requires_every_field() {
+        assert!(serde_json::from_str::<ReceivedMessage>(r#"{"type": "change_avatar", "avatar": 1}"#).is_err());
+        let mex: ReceivedMessage = serde_json::from_str(r#"{"type": "change_avatar", "avatar": 1, "color": 2}"#).unwrap();
+        match mex {
+            ReceivedMessage::ChangeAvatar { cosmetics } => assert!(cosmetics == PlayerCosmetics { avatar: 1, color: 2 }),
+            _ => panic!("Not a change_avatar"),
+        }
+    }
 }
@@ -10,14 +10,18 @@
 //! Additional work is being done to decentralize this, replacing it with a
 //!
 
-use std::{cell::RefCell, collections::{HashMap, HashSet}, iter::Successors, ops::DerefMut, time::Duration};
+use std::{cell::RefCell, collections::{HashMap, HashSet, VecDeque}, iter::Successors, mem, ops::{DerefMut, RangeInclusive}, sync::Arc, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
 
-use actix::dev::{MessageResponse, ResponseChannel};
+use actix::dev::{MessageResponse, ResponseChannel, SendError, ToEnvelope};
 use actix::prelude::*;
+use actix_web::web::Bytes;
 use rand::{self, Rng, rngs::ThreadRng};
+use serde_json::{Map, Value};
 
 use crate::client_ws::ClientWs;
-use crate::protocol::{IdType, LoginData, OutEvent, OutGameEvent, PlayerCosmetics, PlayerObject, RoomConnectionType, SerId};
+use crate::config::Config;
+use crate::locale::Locale;
+use crate::protocol::{AdminPlayerInfo, AdminPlayerList, AdminRoomDump, ChatEntry, CompletedGame, CompletedGameList, RoomLogEntry, ServerCapacity, ServerStats, IdType, LoginData, MAX_COLOR, OutEvent, OutGameEvent, PeerInfo, Platform, PlayerCosmetics, PlayerObject, RoomConnectionType, RoomPeekResponse, SerId, SessionStatus};
 
 // Copied from actix, love the library but it seems a bit rushed in the "actor" part.
 // This should generate the code to share a result between actors.
@@ -37,9 +41,30 @@ macro_rules! simple_result {
     };
 }
 
-const MAX_PLAYERS_PER_ROOM: usize = 5;
-const MIN_PLAYERS_PER_ROOM: usize = 3;
-const ROOM_COUNTDOWN_ON_MIN_PLAYERS: u64 = 10;
+/// Max number of relay deliveries done in a single actor tick, the remaining ones are deferred.
+const RELAY_FANOUT_SLICE: usize = 64;
+/// Number of entries in every page returned by the admin listings.
+const ADMIN_PAGE_SIZE: usize = 100;
+/// Number of events kept in every room's diagnostic log.
+const ROOM_LOG_SIZE: usize = 32;
+/// Relay envelope versions ("v" field) accepted by the server.
+const SUPPORTED_RELAY_VERSIONS: &[u64] = &[1];
+/// How often every room is checked to have exactly one host.
+const HOST_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// How long a player can go back to the room it has just left with RoomRejoin.
+const REJOIN_WINDOW: Duration = Duration::from_secs(60);
+/// Time given to the recipients of a relay message to acknowledge it.
+const RELAY_ACK_TIMEOUT: Duration = Duration::from_secs(5);
+/// Max number of relay packets held by a paused game, the next ones are dropped.
+const PAUSED_RELAY_BACKLOG: usize = 1024;
+/// Window of MAX_ROOM_STARTS_PER_TICK, the delayed room starts are processed at every tick.
+const ROOM_START_TICK: Duration = Duration::from_millis(100);
+/// Number of completed games kept for the stats, the oldest ones are evicted first.
+const COMPLETED_GAMES_SIZE: usize = 256;
+/// Number of chat messages of every room sent to the players that join it.
+const CHAT_HISTORY_SIZE: usize = 20;
+/// How long a rematch waits for the players still finishing the last game, then they're moved out of the room.
+const REMATCH_WAIT: Duration = Duration::from_secs(60);
 
 #[derive(Message)]
 #[rtype(result = "()")]
@@ -49,8 +74,52 @@ pub struct Event(pub OutEvent);
 #[rtype(result = "()")]
 pub struct GameEvent(pub OutGameEvent);
 
+/// Sent to clients that don't keep up with their messages, they should disconnect.
 #[derive(Message)]
-#[rtype(IdType)]
+#[rtype(result = "()")]
+pub struct Unresponsive;
+
+/// Sent to the old connection of a player that has reconnected from a new one, it should disconnect.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SessionReplaced;
+
+/// Sent to every client when the server is stopping, they're told why and disconnected.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ServerShutdown {
+    pub reason: &'static str,
+}
+
+/// The server is going away: the pending relay packets are delivered and every client is disconnected.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Shutdown {
+    pub reason: &'static str,// "restart" or "stop"
+}
+
+/// Returns true once the shutdown has started, the health probe then fails so that no new client is sent here.
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct IsShuttingDown;
+
+/// Looks up the session of a reconnect token without binding it to any connection.
+#[derive(Message)]
+#[rtype(result = "SessionStatus")]
+pub struct GetSessionStatus {
+    pub token: String,
+}
+
+simple_result!(SessionStatus);
+
+/// Error not tied to any client request (ex. a relay packet sent outside of a game).
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ErrorEvent(pub &'static str);
+
+/// Returns the session id and its reconnect token, None if it's a login again of a player in a room (not allowed).
+#[derive(Message)]
+#[rtype(result = "Option<(IdType, String)>")]
 pub struct RegisterSession {
     pub id: Option<IdType>,
     pub addr: Addr<ClientWs>,
@@ -61,15 +130,75 @@ pub struct RegisterSession {
 #[rtype(result = "()")]
 pub struct Disconnect {
     pub id: IdType,
+    pub addr: Addr<ClientWs>,
+}
+
+/// Binds a new connection to the session of a player that is playing, returns None if the session isn't found
+/// (or the token is wrong).
+#[derive(Message)]
+#[rtype(result = "Option<ReconnectInfo>")]
+pub struct ReconnectSession {
+    pub player_id: IdType,
+    pub room_id: IdType,
+    pub token: String,
+    pub addr: Addr<ClientWs>,
+}
+
+pub struct ReconnectInfo {
+    pub is_host: bool,
+    pub locale: Locale,
+}
+
+/// Binds a new connection to the session of the player with the given token, whatever it was doing,
+/// returns None if the session isn't found.
+#[derive(Message)]
+#[rtype(result = "Option<ResumeInfo>")]
+pub struct ResumeSession {
+    pub token: String,
+    pub addr: Addr<ClientWs>,
+}
+
+pub struct ResumeInfo {
+    pub player_id: IdType,
+    pub player: PlayerObject,
+    pub locale: Locale,
+    pub in_game: bool,
+    pub spectating: bool,
+    pub players: Option<Vec<PlayerObject>>,// Roster of its room (or of the watched one), if any.
 }
 
+/// Connection state tracked by the client actor, sent periodically for the admin API.
 #[derive(Message)]
 #[rtype(result = "()")]
+pub struct ReportConnectionStats {
+    pub id: IdType,
+    pub buffered_bytes: u64,// Bytes sent to the client that it hasn't received yet (estimated).
+    pub last_activity: Instant,// Last message received from the client, pings excluded.
+}
+
+/// Returns false if the cosmetics haven't changed.
+#[derive(Message)]
+#[rtype(result = "bool")]
 pub struct EditCosmetics {
     pub id: IdType,
     pub obj: PlayerCosmetics,
 }
 
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SetReady {
+    pub id: IdType,
+    pub ready: bool,
+}
+
+/// Chat message of a player in a lobby, the text is already validated.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ChatMessage {
+    pub sender_id: IdType,
+    pub text: String,
+}
+
 // ----------------------------------------------------------------
 
 #[derive(Message)]
@@ -82,9 +211,11 @@ pub enum FindRoomResult {
     Success {
         room_id: IdType, 
         players: Vec<PlayerObject>,
+        chat_history: Vec<ChatEntry>,
         just_created: bool
     }, 
     GameIsFull,
+    SessionNotFound,
 }
 
 simple_result!(FindRoomResult);
@@ -97,9 +228,14 @@ pub struct CreateRoom {
     pub id: IdType,
 }
 
-pub struct CreateRoomResult {
-    pub room_id: IdType,
-    pub player: PlayerObject,
+pub enum CreateRoomResult {
+    Success {
+        room_id: IdType,
+        player: PlayerObject,
+    },
+    ServerFull,
+    RateLimited,
+    SessionNotFound,
 }
 
 simple_result!(CreateRoomResult);
@@ -111,9 +247,85 @@ pub struct JoinRoom {
     pub room_id: IdType,
 }
 
-pub enum JoinRoomResult {
+/// Returns the current load against the server's caps.
+#[derive(Message)]
+#[rtype(result = "ServerCapacity")]
+pub struct GetCapacity;
+
+simple_result!(ServerCapacity);
+
+/// Returns the counters exposed for the monitoring.
+#[derive(Message)]
+#[rtype(result = "ServerStats")]
+pub struct GetStats;
+
+simple_result!(ServerStats);
+
+/// Sends an announcement to every logged in player, returns the number of recipients (admin only).
+#[derive(Message)]
+#[rtype(result = "usize")]
+pub struct BroadcastAnnouncement {
+    pub text: String,
+    pub translations: HashMap<Locale, String>,// Sent instead of the text to the players with these locales.
+}
+
+/// Creates an empty private room with the given invite id that is never deleted (admin only),
+/// returns false if the id is already taken.
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct CreatePersistentRoom {
+    pub room_id: IdType,
+}
+
+/// Returns the roster of a room without joining it.
+#[derive(Message)]
+#[rtype(result = "Option<RoomPeekResponse>")]
+pub struct PeekRoom {
+    pub room_id: IdType,
+}
+
+simple_result!(RoomPeekResponse);
+
+/// Returns the roster of the player's room, None if it isn't in a room.
+#[derive(Message)]
+#[rtype(result = "Option<Vec<PlayerObject>>")]
+pub struct GetRoster {
+    pub id: IdType,
+}
+
+/// Joins again the last room left by the player, if it has left it recently.
+#[derive(Message)]
+#[rtype(JoinRoomResult)]
+pub struct RejoinRoom {
+    pub id: IdType,
+}
+
+/// Attaches the player to the relay stream of a room that is playing, without taking a seat.
+#[derive(Message)]
+#[rtype(result = "SpectateResult")]
+pub struct SpectateRoom {
+    pub id: IdType,
+    pub room_id: IdType,
+    pub join_next: bool,
+}
+
+pub enum SpectateResult {
     Success(Vec<PlayerObject>),
     RoomNotFound,
+    NotPlaying,
+}
+simple_result!(SpectateResult);
+
+/// Stops watching a room, if the player is a spectator.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct StopSpectating {
+    pub id: IdType,
+}
+
+pub enum JoinRoomResult {
+    Success(Vec<PlayerObject>, Vec<ChatEntry>),// Roster and chat history.
+    RoomNotFound,
     RoomIsFull,
     AlreadyPlaying,
 }
@@ -125,6 +337,23 @@ pub struct LeaveRoom {
     pub id: IdType,
 }
 
+/// Removes a player from the host's room before the game starts.
+#[derive(Message)]
+#[rtype(KickPlayerResult)]
+pub struct KickPlayer {
+    pub host_id: IdType,
+    pub target_id: IdType,
+}
+
+pub enum KickPlayerResult {
+    Success,
+    NotHost,
+    PlayerNotFound,
+    AlreadyPlaying,
+}
+
+simple_result!(KickPlayerResult);
+
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct StartRoom {
@@ -139,10 +368,24 @@ pub struct SendRelayMex {
     pub data: String,
 }
 
+/// Binary packet relayed to the other players prefixed by the sender id.
+#[derive(Message, Clone)]
+#[rtype(result = "()")]
+pub struct SendRelayBinary {
+    pub sender_id: IdType,
+    pub data: Bytes,
+}
+
+#[derive(Clone)]
+pub enum RelayData {
+    Text(String),
+    Binary(Bytes),
+}
+
 #[derive(Message, Clone)]
 #[rtype(result = "()")]
 pub struct SendRelayMexRaw {
-    pub data: String,
+    pub data: RelayData,
 }
 
 #[derive(Message, Clone)]
@@ -154,23 +397,258 @@ pub struct GameEndRequest {
 pub struct GameEndAck(pub Vec<PlayerObject>);
 simple_result!(GameEndAck);
 
+// ----------------------------------------------------------------
+
+/// Lists the connected players (admin only), optionally only the ones in the given room.
+#[derive(Message)]
+#[rtype(AdminPlayerList)]
+pub struct ListPlayers {
+    pub room: Option<IdType>,
+    pub page: usize,
+}
+
+simple_result!(AdminPlayerList);
+
+/// Dumps the state of a room and its recent events (admin only).
+#[derive(Message)]
+#[rtype(result = "Option<AdminRoomDump>")]
+pub struct DumpRoom {
+    pub room_id: IdType,
+}
+
+simple_result!(AdminRoomDump);
+
+/// Returns the summaries of the most recently completed games, the latest first (admin only).
+#[derive(Message)]
+#[rtype(result = "CompletedGameList")]
+pub struct ListCompletedGames;
+
+simple_result!(CompletedGameList);
+
+// ----------------------------------------------------------------
+
+/// Sent by the player that has finished its turn.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct EndTurn {
+    pub id: IdType,
+}
+
+/// Sent by the host to pause (or resume) the game.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct PauseGame {
+    pub id: IdType,
+    pub paused: bool,
+}
+
+/// Sent by a player that has received a relay message that requested an acknowledgement.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct RelayAck {
+    pub id: IdType,
+    pub sender: IdType,
+    pub ack_id: u64,
+}
+
+/// Starts a room once its countdown is over.
+#[derive(Message)]
+#[rtype(result = "()")]
+struct CountdownEnded {
+    room_id: IdType,
+}
+
+/// Removes a disconnected player that hasn't reconnected in time.
+#[derive(Message)]
+#[rtype(result = "()")]
+struct ReconnectGraceEnded {
+    id: IdType,
+}
+
+/// Opens a private room to the matchmaking if it's still waiting for players.
+#[derive(Message)]
+#[rtype(result = "()")]
+struct AutoOpenRoom {
+    room_id: IdType,
+}
+
+/// Continues the relay fanout of a room that exceeded the per-tick slice.
+#[derive(Message)]
+#[rtype(result = "()")]
+struct FlushRelayBacklog {
+    room_id: IdType,
+}
+
 
 struct UserData {
     addr: Addr<ClientWs>,
     obj: PlayerObject,
     room: Option<IdType>,
-    in_game: bool,
+    joined_room_at: Instant,// When it has entered its current room.
+    last_room_create: Option<Instant>,
+    unresponsive: bool,// Its mailbox got full, no more messages are sent until it disconnects.
+
+    // Relay messages sent in the current rate window, used to report suspicious rates.
+    relay_window_start: Instant,
+    relay_window_count: u32,
+    relay_rate_reported: bool,
+
+    roster_diffs: bool,// Wants event_roster_diff instead of the single roster events.
+    locale: Locale,
+    platform: Platform,
+    last_left: Option<(IdType, Instant)>,// Last room left and when, to rejoin it.
+    buffered_bytes: u64,// Last outbound buffer estimate reported by the client actor.
+    last_activity: Instant,// As reported by the client actor.
+
+    reconnect_token: String,
+    disconnected: Option<SpawnHandle>,// Lost its connection, its seat is kept until the handle fires.
+    spectating: Option<IdType>,// Room watched as a spectator, never set together with room.
+    join_next_game: bool,// The spectator wants to join the room when the game ends.
+}
+
+impl UserData {
+    /// Delivers a message to the client without waiting, if its mailbox is full the client is considered
+    /// unresponsive and asked to disconnect (that message isn't bound by the mailbox capacity).
+    fn deliver<M>(&mut self, msg: M)
+        where
+            M: Message + Send + 'static,
+            M::Result: Send,
+            ClientWs: Handler<M>,
+            <ClientWs as Actor>::Context: ToEnvelope<ClientWs, M>,
+    {
+        if self.unresponsive {
+            return;
+        }
+        match self.addr.try_send(msg) {
+            Ok(()) => {},
+            Err(SendError::Full(_)) => {
+                eprintln!("Player {} mailbox is full, disconnecting it", self.obj.id);
+                self.unresponsive = true;
+                self.addr.do_send::<Unresponsive>(Unresponsive);
+            },
+            Err(SendError::Closed(_)) => {},// Already disconnecting
+        }
+    }
+
+    /// Counts a relay message, returns the number of messages sent in the current window the first time
+    /// that the window exceeds the configured rate (it's only reported once per window).
+    fn track_relay_rate(&mut self, config: &Config) -> Option<u32> {
+        if config.relay_rate_alert == 0 {
+            return None;
+        }
+        let window = Duration::from_millis(config.relay_rate_window_ms);
+        if self.relay_window_start.elapsed() >= window {
+            self.relay_window_start = Instant::now();
+            self.relay_window_count = 0;
+            self.relay_rate_reported = false;
+        }
+        self.relay_window_count += 1;
+
+        let limit = config.relay_rate_alert as u64 * config.relay_rate_window_ms / 1000;
+        if !self.relay_rate_reported && self.relay_window_count as u64 > limit {
+            self.relay_rate_reported = true;
+            Some(self.relay_window_count)
+        } else {
+            None
+        }
+    }
 }
 
 struct RoomData {
     state: RoomState,
     players: HashSet<IdType>,
+    spectators: HashSet<IdType>,// They receive the relay packets but don't play.
+    ready: HashSet<IdType>,// Players ready to start, cleared whenever the roster changes.
+    join_order: Vec<IdType>,// The same players, from the earliest joiner (the next host).
     in_game_count: u32,
+    has_played: bool,// At least a game has been started.
+
+    start_countdown_handle: Option<SpawnHandle>,
+    auto_open_handle: Option<SpawnHandle>,
+    start_queued: bool,// Waiting in the start queue because of MAX_ROOM_STARTS_PER_TICK.
+    relay_backlog: VecDeque<PendingRelay>,
+
+    // The current (or last) game.
+    game_started_at: Instant,
+    game_ended_at: Instant,// When the first player came back from it.
+    game_players: Vec<IdType>,
+    connection_type: RoomConnectionType,
+
+    // Turns of the current game, players that left are skipped.
+    turn_order: Vec<IdType>,
+    turn_index: usize,
+    turn_number: u32,
+    paused: bool,// No turn can be ended while the game is paused.
+    persistent: bool,// Created by the admins, it has no host and it's kept when empty.
+
+    relay_count: u64,
+    log: VecDeque<RoomLogEntry>,
+    chat_history: VecDeque<ChatEntry>,// The last messages, for the players that join.
+
+    // Relay messages waiting for acknowledgements, by (sender, ack id).
+    pending_acks: HashMap<(IdType, u64), PendingAck>,
+}
+
+/// A relay packet that still has to be delivered to some of the room's players.
+struct PendingRelay {
+    pkt: SendRelayMexRaw,
+    recipients: Vec<IdType>,
+}
 
-    start_countdown_handle: Option<SpawnHandle>
+/// A relay message that requested delivery receipts.
+struct PendingAck {
+    acked: Vec<IdType>,
+    missing: HashSet<IdType>,
+    timeout_handle: SpawnHandle,
 }
 
 impl RoomData {
+    fn new(players: HashSet<IdType>) -> Self {
+        RoomData {
+            state: RoomState::Matchmaking,
+            join_order: players.iter().copied().collect(),
+            players,
+            spectators: HashSet::new(),
+            ready: HashSet::new(),
+            in_game_count: 0,
+            has_played: false,
+            start_countdown_handle: None,
+            auto_open_handle: None,
+            start_queued: false,
+            relay_backlog: VecDeque::new(),
+            game_started_at: Instant::now(),
+            game_ended_at: Instant::now(),
+            game_players: Vec::new(),
+            connection_type: RoomConnectionType::ServerBroadcast,
+            turn_order: Vec::new(),
+            turn_index: 0,
+            turn_number: 0,
+            paused: false,
+            persistent: false,
+            relay_count: 0,
+            log: VecDeque::new(),
+            chat_history: VecDeque::new(),
+            pending_acks: HashMap::new(),
+        }
+    }
+
+    /// Starts the room after the given seconds.
+    pub fn start_countdown(&mut self, ctx: &mut Context<ServerActor>, room_id: IdType, secs: u64) {
+        let spawn_handle = ctx.notify_later(CountdownEnded {
+            room_id,
+        }, Duration::from_secs(secs));
+        self.start_countdown_handle = Some(spawn_handle);
+        self.log(format!("Countdown started ({} seconds)", secs));
+    }
+
+    /// Appends an event to the room's diagnostic log, the oldest one is evicted if it's full.
+    fn log(&mut self, event: String) {
+        if self.log.len() >= ROOM_LOG_SIZE {
+            self.log.pop_front();
+        }
+        self.log.push_back(RoomLogEntry { time: now_millis(), event });
+    }
+
     pub fn cancel_start_countdown(&mut self, ctx: &mut Context<ServerActor>) -> bool {
         if let Some(handle) = self.start_countdown_handle {
             ctx.cancel_future(handle);
@@ -187,24 +665,44 @@ pub enum RoomState {
     Playing
 }
 
+impl RoomState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RoomState::Matchmaking => "matchmaking",
+            RoomState::Playing => "playing",
+        }
+    }
+}
+
 
 pub struct ServerActor {
     players: HashMap<IdType, UserData>,
     rooms: HashMap<IdType, RoomData>,     // The full list of the rooms.
     pub_rooms: HashSet<IdType>,           // Public rooms created for players that wants to play alone.
-    pub_rooms_available: HashSet<IdType>, // Rooms that are not full.
+    pub_rooms_available: HashMap<IdType, usize>, // Public rooms that can be joined by the matchmaking and their free slots, see refresh_room_availability.
+    available_by_free_slots: Vec<HashSet<IdType>>, // Same rooms indexed by their free slots.
+    warm_rooms: HashSet<IdType>,          // Empty public rooms kept for the next players, see WARM_ROOM_POOL.
+    sessions_by_token: HashMap<String, IdType>, // Players by reconnect token, to resume their sessions.
+    completed_games: VecDeque<CompletedGame>, // Summaries of the last games, see COMPLETED_GAMES_SIZE.
     rng: ThreadRng,
+    config: Arc<Config>,
+
+    // Cosmetics changes broadcast in the current second and the ones delayed because of COSMETICS_BROADCAST_RATE.
+    cosmetics_window_start: Instant,
+    cosmetics_window_count: u32,
+    pending_cosmetics: HashSet<IdType>,
+    cosmetics_flush_scheduled: bool,
+
+    // Rooms started in the current tick and the ones delayed because of MAX_ROOM_STARTS_PER_TICK.
+    room_starts_in_tick: usize,
+    room_start_queue: VecDeque<(IdType, RoomConnectionType, Option<IdType>)>,// With the host that asked for it.
+
+    shutting_down: Option<&'static str>,// Reason of the shutdown, the clients have already been told.
 }
 
 impl Default for ServerActor {
     fn default() -> Self {
-        ServerActor {
-            players: HashMap::new(),
-            rooms: HashMap::new(),
-            pub_rooms: HashSet::new(),
-            pub_rooms_available: HashSet::new(),
-            rng: rand::thread_rng(),
-        }
+        ServerActor::new(Arc::new(Config::default()))
     }
 }
 
@@ -212,9 +710,77 @@ impl Actor for ServerActor {
     /// We are going to use simple Context, we just need ability to communicate
     /// with other actors.
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.set_mailbox_capacity(self.config.server_mailbox_capacity);
+        ctx.run_interval(HOST_CHECK_INTERVAL, |act, _| {
+            let room_ids: Vec<IdType> = act.rooms.keys().copied().collect();
+            for room_id in room_ids {
+                act.check_room_host(room_id);
+            }
+        });
+        if self.config.max_room_starts_per_tick > 0 {
+            ctx.run_interval(ROOM_START_TICK, |act, ctx| act.process_start_queue(ctx));
+        }
+    }
+
+    fn stopping(&mut self, _ctx: &mut Self::Context) -> Running {
+        println!("Server stopping, disconnecting {} players", self.players.len());
+        if self.shutting_down.is_none() {
+            for player in self.players.values() {
+                player.addr.do_send(ServerShutdown { reason: "stop" });
+            }
+        }
+        self.players.clear();
+        self.sessions_by_token.clear();
+        self.rooms.clear();
+        self.pub_rooms.clear();
+        self.pub_rooms_available.clear();
+        self.available_by_free_slots.iter_mut().for_each(HashSet::clear);
+        Running::Stop
+    }
 }
 
 impl ServerActor {
+    pub fn new(config: Arc<Config>) -> Self {
+        ServerActor {
+            players: HashMap::new(),
+            rooms: HashMap::new(),
+            pub_rooms: HashSet::new(),
+            pub_rooms_available: HashMap::new(),
+            available_by_free_slots: vec![HashSet::new(); config.max_players + 1],
+            warm_rooms: HashSet::new(),
+            sessions_by_token: HashMap::new(),
+            rng: rand::thread_rng(),
+            config,
+            cosmetics_window_start: Instant::now(),
+            cosmetics_window_count: 0,
+            pending_cosmetics: HashSet::new(),
+            cosmetics_flush_scheduled: false,
+            room_starts_in_tick: 0,
+            room_start_queue: VecDeque::new(),
+            completed_games: VecDeque::new(),
+            shutting_down: None,
+        }
+    }
+
+    /// Replaces the default cosmetics (avatar 0, color 0) with random ones if enabled in the config.
+    fn assign_default_cosmetics(&mut self, cosmetics: &mut PlayerCosmetics) {
+        if !self.config.auto_cosmetics || *cosmetics != PlayerCosmetics::default() {
+            return;
+        }
+        if self.config.avatar_count > 0 {
+            cosmetics.avatar = self.rng.gen_range(0, self.config.avatar_count);
+        }
+        cosmetics.color = self.rng.gen_range(1, MAX_COLOR + 1);// Any color but black
+    }
+
+    fn remove_player(&mut self, id: IdType) {
+        if let Some(player) = self.players.remove(&id) {
+            self.sessions_by_token.remove(&player.reconnect_token);
+        }
+    }
+
     fn allocate_player_id(&mut self, mut data: UserData) -> IdType {
         let mut id;
 
@@ -230,11 +796,22 @@ impl ServerActor {
         id
     }
 
-    fn create_room(&mut self, host_id: IdType, public: bool) -> IdType {
+    /// Creates a room hosted by `host_id`, returns None if the host's session doesn't exist (anymore).
+    fn create_room(&mut self, host_id: IdType, public: bool) -> Option<IdType> {
+        if !self.players.contains_key(&host_id) {
+            return None;
+        }
         let mut id;
 
+        // Private rooms are joined by invite id so they might use a shorter one.
+        let id_mask = if public || self.config.invite_id_bytes >= mem::size_of::<IdType>() {
+            IdType::MAX
+        } else {
+            (1 << (self.config.invite_id_bytes * 8)) - 1
+        };
+
         loop {
-            id = self.rng.gen::<IdType>();
+            id = self.rng.gen::<IdType>() & id_mask;
 
             if !self.rooms.contains_key(&id) {
                 break;
@@ -243,58 +820,297 @@ impl ServerActor {
 
         let mut players = HashSet::new();
         players.insert(host_id);
-        let room = RoomData {
-            state: RoomState::Matchmaking,
-            players,
-            in_game_count: 0,
-            start_countdown_handle: None
-        };
-        self.rooms.insert(id, room);
+        self.rooms.insert(id, RoomData::new(players));
 
         let host = self.players.get_mut(&host_id).unwrap();
         host.obj.is_host = true;
         host.room = Some(id);
+        host.joined_room_at = Instant::now();
+        self.rooms.get_mut(&id).unwrap().log(format!("Created by {} ({})", host.obj.id, if public { "public" } else { "private" }));
 
         if public {
             self.pub_rooms.insert(id);
-            self.pub_rooms_available.insert(id); // As soon as it is created, the pub room is available.
+        }
+        self.refresh_room_availability(id);
+        if cfg!(debug_assertions) {
+            self.check_room_host(id);
         }
 
-        id
+        Some(id)
     }
 
-    fn remove_room(&mut self, room_id: IdType) {
-        self.rooms.remove(&room_id);
-        self.pub_rooms.remove(&room_id);
-        self.pub_rooms_available.remove(&room_id);
-
-        //println!("room removed (id={}) because it's empty", room_id);
+    fn is_server_full(&self) -> bool {
+        self.config.max_rooms > 0 && self.rooms.len() >= self.config.max_rooms
     }
 
-    /// Send event to all users in the room
-    fn broadcast_event(room: &RoomData, players_by_id: &HashMap<IdType, UserData>, event: OutEvent, skip_id: Option<IdType>) {
-        ServerActor::broadcast_event_room(room, players_by_id, event, skip_id);
+    fn playing_room_count(&self) -> usize {
+        self.rooms.values().filter(|x| x.state == RoomState::Playing).count()
     }
 
-    fn broadcast_event_room(room_data: &RoomData, players_by_id: &HashMap<IdType, UserData>, event: OutEvent, skip_id: Option<IdType>) {
-        for id in room_data.players.iter() {
-            if Some(*id) == skip_id {
-                continue;
-            }
-            let player = match players_by_id.get(&id) {
-                Some(x) => x,
-                None => continue,
-            };
-
-            if player.in_game {
-                continue; // Don't send if player is still in the game.
-            }
-            player.addr.do_send(Event(event.clone()));// TODO: remove clone
-        }
+    /// No other game can start because of MAX_GAMES.
+    fn is_game_capacity_full(&self) -> bool {
+        self.config.max_games > 0 && self.playing_room_count() >= self.config.max_games
     }
 
-    fn leave_room_if_any(&mut self, ctx: &mut Context<Self>, player_id: IdType) {
-
+    /// Recomputes whether the room can be found by the matchmaking, to be called after any change to the room.
+    /// Only public rooms that are waiting for players and aren't full are available, rooms that have already played
+    /// a game are only available with rematchmaking.
+    fn refresh_room_availability(&mut self, room_id: IdType) {
+        let rematchmaking = self.config.rematchmaking;
+        let available = self.pub_rooms.contains(&room_id) && self.rooms.get(&room_id).is_some_and(|room| {
+            room.state == RoomState::Matchmaking
+                && room.players.len() < self.config.max_players
+                && (!room.has_played || rematchmaking)
+        });
+
+        let free_slots = if available {
+            Some(self.config.max_players - self.rooms[&room_id].players.len())
+        } else {
+            None
+        };
+        let old_free_slots = match free_slots {
+            Some(x) => self.pub_rooms_available.insert(room_id, x),
+            None => self.pub_rooms_available.remove(&room_id),
+        };
+        if old_free_slots == free_slots {
+            return;
+        }
+        if let Some(x) = old_free_slots {
+            self.available_by_free_slots[x].remove(&room_id);
+        }
+        if let Some(x) = free_slots {
+            self.available_by_free_slots[x].insert(room_id);
+        }
+    }
+
+    /// Counts a cosmetics broadcast against the server-wide rate, returns false if it has to be delayed.
+    fn take_cosmetics_broadcast(&mut self) -> bool {
+        if self.config.cosmetics_broadcast_rate == 0 {
+            return true;
+        }
+        if self.cosmetics_window_start.elapsed() >= Duration::from_secs(1) {
+            self.cosmetics_window_start = Instant::now();
+            self.cosmetics_window_count = 0;
+        }
+        if self.cosmetics_window_count >= self.config.cosmetics_broadcast_rate {
+            return false;
+        }
+        self.cosmetics_window_count += 1;
+        true
+    }
+
+    /// Sends the player's current cosmetics to the other players of its room.
+    fn broadcast_cosmetics(&mut self, player_id: IdType) {
+        let (room_id, obj) = match self.players.get(&player_id) {
+            Some(UserData { room: Some(room_id), obj, .. }) => (*room_id, obj.clone()),
+            _ => return,
+        };
+        let room = match self.rooms.get(&room_id) {
+            Some(x) => x,
+            None => return,
+        };
+        let event = OutEvent::EventPlayerAvatarChange {
+            player: player_id.into(),
+            cosmetics: obj.cosmetics.clone(),
+        };
+        let diff = OutEvent::EventRosterDiff {
+            added: Vec::new(),
+            removed: Vec::new(),
+            changed: vec![obj],
+        };
+        ServerActor::broadcast_roster_event(room, &mut self.players, event, diff, Some(player_id));
+    }
+
+    /// Broadcasts the delayed cosmetics changes that fit in the current window, the rest waits for the next one.
+    fn flush_pending_cosmetics(&mut self, ctx: &mut Context<Self>) {
+        self.cosmetics_flush_scheduled = false;
+
+        let pending: Vec<IdType> = self.pending_cosmetics.iter().copied().collect();
+        for player_id in pending {
+            if !self.take_cosmetics_broadcast() {
+                break;
+            }
+            self.pending_cosmetics.remove(&player_id);
+            self.broadcast_cosmetics(player_id);
+        }
+
+        if !self.pending_cosmetics.is_empty() {
+            self.schedule_cosmetics_flush(ctx);
+        }
+    }
+
+    fn schedule_cosmetics_flush(&mut self, ctx: &mut Context<Self>) {
+        if self.cosmetics_flush_scheduled {
+            return;
+        }
+        self.cosmetics_flush_scheduled = true;
+        let next_window = Duration::from_secs(1).checked_sub(self.cosmetics_window_start.elapsed()).unwrap_or_default();
+        ctx.run_later(next_window, |act, ctx| act.flush_pending_cosmetics(ctx));
+    }
+
+    /// Checks that the room has exactly one host, if it doesn't the first host (or player) is kept as the only one.
+    /// It runs after every membership change in debug builds and periodically for every room.
+    fn check_room_host(&mut self, room_id: IdType) {
+        let room = match self.rooms.get(&room_id) {
+            Some(x) => x,
+            None => return,
+        };
+        let hosts: Vec<IdType> = room.players.iter()
+            .copied()
+            .filter(|x| self.players.get(x).is_some_and(|p| p.obj.is_host))
+            .collect();
+        if room.persistent {
+            // Nobody owns the room.
+            if hosts.is_empty() {
+                return;
+            }
+            eprintln!("Persistent room {} has {} hosts, fixing it", SerId(room_id), hosts.len());
+            for id in hosts.iter() {
+                self.players.get_mut(id).unwrap().obj.is_host = false;
+            }
+            self.rooms.get_mut(&room_id).unwrap().log(format!("Host invariant broken ({} hosts in a persistent room)", hosts.len()));
+            return;
+        }
+        if hosts.len() == 1 || room.players.is_empty() {
+            return;
+        }
+
+        eprintln!("Room {} has {} hosts, fixing it", SerId(room_id), hosts.len());
+        let host = *hosts.first().or_else(|| room.join_order.first()).unwrap();
+        for id in room.players.iter() {
+            if let Some(player) = self.players.get_mut(id) {
+                let was_host = player.obj.is_host;
+                player.obj.is_host = *id == host;
+                if player.obj.is_host && !was_host {
+                    // Its client has to know, or it would reject its own host actions.
+                    player.deliver(Event(OutEvent::EventHostAssigned {}));
+                }
+            }
+        }
+        self.rooms.get_mut(&room_id).unwrap().log(format!("Host invariant broken ({} hosts), {} is the host now", hosts.len(), SerId(host)));
+    }
+
+    fn remove_room(&mut self, ctx: &mut Context<Self>, room_id: IdType) {
+        // The scheduled futures would find no room anyway, but there's no need to wake up for them.
+        if let Some(mut room) = self.rooms.remove(&room_id) {
+            ServerActor::end_spectating(&mut room, &mut self.players);
+            room.cancel_start_countdown(ctx);
+            if let Some(handle) = room.auto_open_handle {
+                ctx.cancel_future(handle);
+            }
+            for (_, ack) in room.pending_acks.drain() {
+                ctx.cancel_future(ack.timeout_handle);
+            }
+        }
+        self.pub_rooms.remove(&room_id);
+        self.warm_rooms.remove(&room_id);
+        self.refresh_room_availability(room_id);
+
+        //println!("room removed (id={}) because it's empty", room_id);
+    }
+
+    /// Sends the spectators of the room back to the matchmaking, there's no game to watch anymore.
+    fn end_spectating(room: &mut RoomData, players_by_id: &mut HashMap<IdType, UserData>) {
+        for id in room.spectators.drain() {
+            if let Some(player) = players_by_id.get_mut(&id) {
+                player.spectating = None;
+                player.join_next_game = false;
+                player.deliver(Event(OutEvent::EventSpectateEnded { players: None }));
+            }
+        }
+    }
+
+    /// Ends the spectating of a game that is over, the spectators that want to play join the room if enabled
+    /// (as long as there are free seats), the others go back to the matchmaking.
+    fn end_spectating_after_game(&mut self, ctx: &mut Context<Self>, room_id: IdType) {
+        let spectators: Vec<IdType> = match self.rooms.get_mut(&room_id) {
+            Some(room) => room.spectators.drain().collect(),
+            None => return,
+        };
+        for id in spectators {
+            let player = match self.players.get_mut(&id) {
+                Some(x) => x,
+                None => continue,
+            };
+            player.spectating = None;
+            let join = self.config.spectators_join_after_game && mem::take(&mut player.join_next_game);
+
+            let players = if join {
+                match self.join_room(ctx, id, room_id) {
+                    JoinRoomResult::Success(players, _) => Some(players),
+                    _ => None,// Full, the spectator goes back to the matchmaking.
+                }
+            } else {
+                None
+            };
+            if let Some(player) = self.players.get_mut(&id) {
+                player.deliver(Event(OutEvent::EventSpectateEnded { players }));
+            }
+        }
+    }
+
+    fn stop_spectating(&mut self, player_id: IdType) {
+        let player = match self.players.get_mut(&player_id) {
+            Some(x) => x,
+            None => return,
+        };
+        player.join_next_game = false;
+        let room_id = match player.spectating.take() {
+            Some(x) => x,
+            None => return,
+        };
+        if let Some(room) = self.rooms.get_mut(&room_id) {
+            room.spectators.remove(&player_id);
+        }
+    }
+
+    /// Sends the event to the players of the room that are still in its game.
+    fn broadcast_game_event(room_data: &RoomData, players_by_id: &HashMap<IdType, UserData>, event: OutGameEvent, skip_id: Option<IdType>) {
+        for id in room_data.players.iter() {
+            if Some(*id) == skip_id {
+                continue;
+            }
+            if let Some(player) = players_by_id.get(id) {
+                if player.obj.in_game {
+                    player.addr.do_send(GameEvent(event.clone()));
+                }
+            }
+        }
+    }
+
+    /// Nobody is ready anymore after a roster change, the room is told about the players that were.
+    fn reset_ready(room_data: &mut RoomData, players_by_id: &mut HashMap<IdType, UserData>) {
+        for id in mem::take(&mut room_data.ready) {
+            let event = OutEvent::EventPlayerReady {
+                player: id.into(),
+                ready: false,
+            };
+            ServerActor::broadcast_roster_event(room_data, players_by_id, event.clone(), event, None);
+        }
+    }
+
+    /// Sends a roster event to all the users in the room that aren't in game,
+    /// the players that asked for roster diffs get `diff` instead.
+    fn broadcast_roster_event(room_data: &RoomData, players_by_id: &mut HashMap<IdType, UserData>, event: OutEvent, diff: OutEvent, skip_id: Option<IdType>) {
+        for id in room_data.players.iter() {
+            if Some(*id) == skip_id {
+                continue;
+            }
+            let player = match players_by_id.get_mut(id) {
+                Some(x) => x,
+                None => continue,
+            };
+
+            if player.obj.in_game {
+                continue; // Don't send if player is still in the game.
+            }
+            let event = if player.roster_diffs { diff.clone() } else { event.clone() };
+            player.deliver(Event(event));
+        }
+    }
+
+    fn leave_room_if_any(&mut self, ctx: &mut Context<Self>, player_id: IdType) {
+
         let player = match self.players.get_mut(&player_id) {
             Some(x) => x,
             None => return,
@@ -306,34 +1122,47 @@ impl ServerActor {
 
         let room = self.rooms.get_mut(&room_id).expect("Cannot find room");
         room.players.remove(&player_id);
+        room.join_order.retain(|x| *x != player_id);
+        room.ready.remove(&player_id);
 
-        if room.players.len() < MIN_PLAYERS_PER_ROOM { // If the players count becomes lower than the min number of players stops the countdown.
+        if room.players.len() < self.config.min_players { // If the players count becomes lower than the min number of players stops the countdown.
             if room.cancel_start_countdown(ctx) {
                 println!("[LeaveRoom] Room {}'s countdown has been canceled because a player quit.", room_id);
+                room.log("Countdown canceled".to_string());
+            }
+            if mem::take(&mut room.start_queued) {
+                self.room_start_queue.retain(|x| x.0 != room_id);
+                room.log("Delayed start canceled".to_string());
             }
         }
 
-        // If the room is public and a player's quit and the number of players is less than the max, the room is available.
-        if self.pub_rooms.contains(&room_id) && room.players.len() < MAX_PLAYERS_PER_ROOM {
-            self.pub_rooms_available.insert(room_id);
-        }
-
-        if player.in_game {
+        // The last one out of a game that has already ended, nothing is left to spectate.
+        let ended_game = player.obj.in_game && room.state == RoomState::Matchmaking && room.in_game_count == 1;
+        if player.obj.in_game {
             room.in_game_count -= 1;
         }
 
+        let was_player_turn = room.state == RoomState::Playing && room.turn_order.get(room.turn_index) == Some(&player_id);
         let was_player_host = player.obj.is_host;
         player.room = None;
         player.obj.is_host = false;
+        player.last_left = Some((room_id, Instant::now()));
+        room.log(format!("{} left", SerId(player_id)));
 
-        if let Some(first_player) = room.players.iter().next() {
-            let new_host = if was_player_host {
-                let mut p = self.players.get_mut(first_player).expect("Invalid player");
+        // The countdown isn't affected by the host change.
+        if let Some(first_player) = room.join_order.first() {
+            let new_host_obj = if was_player_host && !room.persistent {
+                let p = self.players.get_mut(first_player).expect("Invalid player");
                 p.obj.is_host = true;
-                Some(p.obj.id)
+                p.deliver(Event(OutEvent::EventHostAssigned {}));
+                Some(p.obj.clone())
             } else {
                 None
             };
+            let new_host = new_host_obj.as_ref().map(|x| x.id);
+            if let Some(new_host) = new_host {
+                room.log(format!("{} is the new host", new_host));
+            }
 
             // Why cant I convert a mutable reference to an immutable one? wtf
             // let room = &*room;
@@ -349,369 +1178,2634 @@ impl ServerActor {
                 new_host
             };
 
+            let diff = OutEvent::EventRosterDiff {
+                added: Vec::new(),
+                removed: vec![player_id.into()],
+                changed: new_host_obj.into_iter().collect(),
+            };
+
             for id in room.players.iter() {
                 let player = match self.players.get(&id) {
                     Some(x) => x,
                     None => continue,
                 };
 
-                if player.in_game {
+                if player.obj.in_game {
                     player.addr.do_send(GameEvent(in_game_event.clone()));
+                } else if player.roster_diffs {
+                    player.addr.do_send(Event(diff.clone()));
                 } else {
                     player.addr.do_send(Event(event.clone()));// TODO: remove clone
                 }
             }
+            ServerActor::reset_ready(room, &mut self.players);
+        } else if room.persistent {
+            // Wait for the next players, whatever was going on.
+            room.state = RoomState::Matchmaking;
+            room.chat_history.clear();
+            room.turn_order.clear();
+            room.paused = false;
+            room.relay_backlog.clear();
+            ServerActor::end_spectating(room, &mut self.players);
+            for (_, ack) in room.pending_acks.drain() {
+                ctx.cancel_future(ack.timeout_handle);
+            }
+            println!("[LeaveRoom] Room {} is empty but persistent, keeping it.", room_id);
+        } else if self.pub_rooms.contains(&room_id) && room.state == RoomState::Matchmaking && !room.has_played
+                && self.warm_rooms.len() < self.config.warm_room_pool {
+            // Rooms that have hosted a game are deleted anyway, they carry its state.
+            room.log("Kept warm".to_string());
+            room.chat_history.clear();
+            self.warm_rooms.insert(room_id);
+            println!("[LeaveRoom] Room {} has been kept warm since all players quit.", room_id);
         } else {
-            self.remove_room(room_id);
+            self.remove_room(ctx, room_id);
             println!("[LeaveRoom] Room {} has been deleted since all players quit.", room_id);
         }
+        self.refresh_room_availability(room_id);
+        if cfg!(debug_assertions) {
+            self.check_room_host(room_id);
+        }
+
+        if was_player_turn {
+            self.advance_turn(room_id);
+        }
+        if ended_game {
+            self.end_spectating_after_game(ctx, room_id);
+        }
+    }
+
+    /// Passes the turn to the next player of the turn order that is still in the game.
+    fn advance_turn(&mut self, room_id: IdType) {
+        let room = match self.rooms.get_mut(&room_id) {
+            Some(x) => x,
+            None => return,
+        };
+        let players = &self.players;
+
+        let len = room.turn_order.len();
+        let next = (1..=len)
+            .map(|i| (room.turn_index + i) % len)
+            .find(|i| {
+                let id = room.turn_order[*i];
+                // The players waiting to reconnect are skipped too, their turn would stall the game until they're back.
+                room.players.contains(&id) && players.get(&id).is_some_and(|x| x.obj.in_game && x.disconnected.is_none())
+            });
+        let next = match next {
+            Some(x) => x,
+            None => return,
+        };
+
+        room.turn_index = next;
+        room.turn_number += 1;
+
+        let event = OutGameEvent::TurnStarted {
+            player: room.turn_order[next].into(),
+            turn_number: room.turn_number,
+        };
+        for id in room.players.iter() {
+            if let Some(player) = players.get(id) {
+                if player.obj.in_game {
+                    player.addr.do_send(GameEvent(event.clone()));
+                }
+            }
+        }
+    }
+
+    /// Tells the sender of an acknowledged relay message who has (and hasn't) received it.
+    fn finish_relay_ack(&mut self, ctx: &mut Context<Self>, room_id: IdType, key: (IdType, u64)) {
+        let pending = match self.rooms.get_mut(&room_id).and_then(|room| room.pending_acks.remove(&key)) {
+            Some(x) => x,
+            None => return,
+        };
+        ctx.cancel_future(pending.timeout_handle);
+
+        let (sender_id, ack_id) = key;
+        if let Some(sender) = self.players.get(&sender_id) {
+            sender.addr.do_send(GameEvent(OutGameEvent::RelayDelivered {
+                message_id: ack_id,
+                acked: pending.acked.into_iter().map(SerId).collect(),
+                missing: pending.missing.into_iter().map(SerId).collect(),
+            }));
+        }
+    }
+
+    /// Why the room can't start now, None if it can. The host has already been checked.
+    fn start_failure(&self, room_id: IdType, conn_type: RoomConnectionType) -> Option<&'static str> {
+        let room = self.rooms.get(&room_id)?;
+        if self.config.strict_min_players && room.players.len() < self.config.min_players {
+            return Some("below_minimum");
+        }
+        let dwell = Duration::from_millis(self.config.min_lobby_dwell_ms);
+        let too_early = room.players.iter().any(|id| {
+            self.players.get(id).is_some_and(|x| x.joined_room_at.elapsed() < dwell)
+        });
+        if too_early {
+            return Some("too_early");
+        }
+        if self.is_game_capacity_full() {
+            return Some("server_game_capacity");
+        }
+        if self.config.require_ready && room.ready.len() < room.players.len() {
+            return Some("not_ready");
+        }
+        if !self.config.connection_types_for(room.players.len()).contains(&conn_type) {
+            return Some("connection_type_unavailable");
+        }
+        None
+    }
+
+    /// Starts the room, or queues the start if too many rooms have already started in the current tick.
+    /// `host` is the player that requested it, None for the countdowns.
+    fn start_room(&mut self, ctx: &mut Context<Self>, room_id: IdType, conn_type: RoomConnectionType, host: Option<IdType>) {
+        let limit = self.config.max_room_starts_per_tick;
+        if limit == 0 || (self.room_start_queue.is_empty() && self.room_starts_in_tick < limit) {
+            self.room_starts_in_tick += 1;
+            self.start_room_now(ctx, room_id, conn_type);
+            return;
+        }
+
+        let room = match self.rooms.get_mut(&room_id) {
+            Some(x) => x,
+            None => return,
+        };
+        room.cancel_start_countdown(ctx);
+        if room.start_queued || room.state != RoomState::Matchmaking {
+            return;
+        }
+        room.start_queued = true;
+        room.log("Start delayed, too many rooms are starting".to_string());
+        self.room_start_queue.push_back((room_id, conn_type, host));
+    }
+
+    /// Starts the queued rooms that fit in the new tick.
+    fn process_start_queue(&mut self, ctx: &mut Context<Self>) {
+        self.room_starts_in_tick = 0;
+        while self.room_starts_in_tick < self.config.max_room_starts_per_tick {
+            let (room_id, mut conn_type, host) = match self.room_start_queue.pop_front() {
+                Some(x) => x,
+                None => break,
+            };
+            let room = match self.rooms.get_mut(&room_id) {
+                Some(x) if x.start_queued => x,
+                _ => continue,// Closed in the meantime, it doesn't count.
+            };
+            room.start_queued = false;
+            let player_count = room.players.len();
+
+            // The room may have changed while it was waiting.
+            if let Some(host) = host {
+                let player = match self.players.get(&host) {
+                    Some(x) if x.room == Some(room_id) => x,
+                    _ => continue,// Left, the new host can request it again.
+                };
+                let failure = if player.obj.is_host { self.start_failure(room_id, conn_type) } else { Some("not_host") };
+                if let Some(reason) = failure {
+                    player.addr.do_send(Event(OutEvent::EventRoomStartFailed { reason }));
+                    continue;
+                }
+            } else if !self.config.connection_types_for(player_count).contains(&conn_type) {
+                conn_type = RoomConnectionType::ServerBroadcast;
+            }
+            self.room_starts_in_tick += 1;
+            self.start_room_now(ctx, room_id, conn_type);
+        }
+    }
+
+    fn start_room_now(&mut self, ctx: &mut Context<Self>, room_id: IdType, conn_type: RoomConnectionType) {
+        if self.is_game_capacity_full() {
+            // The capacity has been reached while the start was queued, the room stays in its lobby.
+            println!("[StartRoom] Room {} can't start, too many games are running.", room_id);
+            let room = match self.rooms.get_mut(&room_id) {
+                Some(x) if x.state == RoomState::Matchmaking => x,
+                _ => return,
+            };
+            room.log("Start failed, too many games are running".to_string());
+            if room.persistent {
+                // Nobody can start it again, try later like CountdownEnded.
+                room.start_countdown(ctx, room_id, self.config.start_countdown_secs.max(1));
+                return;
+            }
+            let players = &self.players;
+            let host = room.players.iter()
+                .filter_map(|id| players.get(id))
+                .find(|x| x.obj.is_host);
+            if let Some(host) = host {
+                host.addr.do_send(Event(OutEvent::EventRoomStartFailed { reason: "server_game_capacity" }));
+            }
+            return;
+        }
+        println!("[StartRoom] Room {} is starting.", room_id);
+
+        if let Some(room) = self.rooms.get_mut(&room_id) {
+
+            // Ensures that there wasn't any "lobby" countdown running.
+            room.cancel_start_countdown(ctx);
+
+            if room.state != RoomState::Matchmaking {
+                return
+            }
+            if room.players.len() < 2 {
+                return
+            }
+
+            room.state = RoomState::Playing;
+            room.has_played = true;
+            room.ready.clear();
+            room.turn_order.clear();
+
+            let room = if room.in_game_count > 0 {
+                // Kick players that are still in-game
+                let mut in_game_players = vec![];
+                for id in room.players.iter() {
+                    if let Some(x) = self.players.get_mut(id) {
+                        if x.obj.in_game {
+                            in_game_players.push(*id);
+                        }
+                    }
+                }
+
+                for id in in_game_players {
+                    self.leave_room_if_any(ctx, id);
+                }
+
+                match self.rooms.get_mut(&room_id) {
+                    None => return,
+                    Some(x) => x,
+                }
+            } else {
+                room
+            };
+
+            let turn_order: Vec<IdType> = room.players.iter().copied().collect();
+            room.game_started_at = Instant::now();
+            room.game_players = turn_order.clone();
+            room.connection_type = conn_type;
+            room.paused = false;
+            room.relay_backlog.clear();// Left by the last game's players.
+            room.turn_index = 0;
+            room.turn_number = 0;
+            if conn_type.is_relayed() {
+                room.turn_order = turn_order.clone();
+            }
+
+            // Peer to peer games only get the hints to connect to each other, the players' peer index is their turn.
+            let event = OutEvent::EventRoomStart {
+                connection_type: conn_type,
+                broadcast_id: if conn_type == RoomConnectionType::ServerBroadcast { Some(room_id.into()) } else { None },
+                peers: if conn_type == RoomConnectionType::PeerToPeer {
+                    Some(turn_order.iter().enumerate().map(|(i, x)| PeerInfo {
+                        player: SerId(*x),
+                        peer_index: i as u32,
+                    }).collect())
+                } else {
+                    None
+                },
+                turn_order: turn_order.iter().map(|x| SerId(*x)).collect(),
+            };
+
+            for id in room.players.iter() {
+                if let Some(x) = self.players.get_mut(id) {
+                    x.obj.in_game = true;
+                    x.addr.do_send(Event(event.clone()));// TODO: remove clone
+                }
+            }
+            room.in_game_count = room.players.len() as u32;
+            room.log(format!("Game started with {} players", room.in_game_count));
+        }
+        self.refresh_room_availability(room_id);
+    }
+
+    fn join_room(&mut self, ctx: &mut Context<Self>, my_id: IdType, room_id: IdType) -> JoinRoomResult {
+        self.leave_room_if_any(ctx, my_id);
+
+        let players_by_id = &mut self.players;
+
+        let room_data = match self.rooms.get_mut(&room_id) {
+            Some(room_data) => room_data,
+            None => return JoinRoomResult::RoomNotFound
+        };
+
+        if room_data.state != RoomState::Matchmaking {
+            return JoinRoomResult::AlreadyPlaying;
+        }
+
+        if room_data.players.len() >= self.config.max_players {
+            return JoinRoomResult::RoomIsFull;
+        }
+
+        ServerActor::reset_ready(room_data, players_by_id);
+        room_data.players.insert(my_id);
+        room_data.join_order.push(my_id);
+        room_data.log(format!("{} joined", SerId(my_id)));
+        
+        let user_data = players_by_id.get_mut(&my_id).unwrap();
+        user_data.room = Some(room_id);
+        user_data.joined_room_at = Instant::now();
+        if self.warm_rooms.remove(&room_id) {
+            // Nobody else is in the room.
+            user_data.obj.is_host = true;
+        }
+
+        let player = user_data.obj.clone();
+        let diff = OutEvent::EventRosterDiff {
+            added: vec![player.clone()],
+            removed: Vec::new(),
+            changed: Vec::new(),
+        };
+        ServerActor::broadcast_roster_event(
+            room_data,
+            players_by_id, 
+            OutEvent::EventPlayerJoined { player }, 
+            diff,
+            None
+        );
+        
+        println!("[JoinRoom] Room {} joined by the player {}.", room_id, my_id);
+        
+        if room_data.players.len() == self.config.min_players {
+            room_data.start_countdown(ctx, room_id, self.config.start_countdown_secs);
+
+            println!("[JoinRoom] Room {} has reached the min players ({}), it's going to start in {} seconds.", room_id, self.config.min_players, self.config.start_countdown_secs);
+        }
+
+        let players = room_data.players.iter().map(|id| players_by_id.get(id).unwrap().obj.clone()).collect();
+        let chat_history = room_data.chat_history.iter().cloned().collect();
+        // If the max players are reached the room isn't available anymore.
+        self.refresh_room_availability(room_id);
+        if cfg!(debug_assertions) {
+            self.check_room_host(room_id);
+        }
+
+        JoinRoomResult::Success(players, chat_history)
+    }
+
+    /// Returns one of the available public rooms with the least free slots in the range.
+    fn find_available_room(&self, free_slots: RangeInclusive<usize>) -> Option<IdType> {
+        free_slots
+            .filter_map(|x| self.available_by_free_slots.get(x))
+            .find_map(|rooms| rooms.iter().next().copied())
+    }
+
+    /// Returns the room where the sender's relay packets go, senders that aren't in game are told to stop relaying.
+    /// Players relaying at suspicious rates are reported (their packets are still relayed).
+    fn relay_room_of(&mut self, sender_id: IdType) -> Option<IdType> {
+        let player = self.players.get_mut(&sender_id).expect("Expected player");
+        let room_id = match player.room {
+            Some(x) if player.obj.in_game => x,
+            _ => {
+                // The client still thinks it's playing (ex. the game has just ended), tell it to stop relaying.
+                player.addr.do_send(ErrorEvent("not_in_game"));
+                return None;
+            },
+        };
+        let room = self.rooms.get_mut(&room_id)?;
+
+        if let Some(count) = player.track_relay_rate(&self.config) {
+            eprintln!("[Relay] Player {} sent {} relay messages in {}ms", sender_id, count, self.config.relay_rate_window_ms);
+            room.log(format!("Relay rate alert for player {}: {} messages in {}ms", sender_id, count, self.config.relay_rate_window_ms));
+        }
+        Some(room_id)
+    }
+
+    /// Queues a relay packet for every other player of the room and its spectators (if `spectators` is set).
+    fn enqueue_relay(&mut self, ctx: &mut Context<Self>, room_id: IdType, sender_id: IdType, data: RelayData, spectators: bool) {
+        let room = self.rooms.get_mut(&room_id).unwrap();
+        if room.paused && room.relay_backlog.len() >= PAUSED_RELAY_BACKLOG {
+            if let Some(sender) = self.players.get(&sender_id) {
+                sender.addr.do_send(ErrorEvent("game_paused"));
+            }
+            return;
+        }
+        room.relay_count += 1;
+        let recipients = room.players.iter()
+            .chain(room.spectators.iter().filter(|_| spectators))
+            .filter(|x| **x != sender_id)
+            .copied()
+            .collect();
+
+        // If there's already a backlog the flush is already scheduled, just enqueue the packet.
+        let was_idle = room.relay_backlog.is_empty();
+        room.relay_backlog.push_back(PendingRelay {
+            pkt: SendRelayMexRaw { data },
+            recipients,
+        });
+        if was_idle {
+            self.flush_relay_backlog(ctx, room_id);
+        }
+    }
+
+    /// Delivers the room's pending relay packets, at most RELAY_FANOUT_SLICE per call.
+    /// If something is left another flush is queued so that other messages can be processed in the meantime,
+    /// packets are always delivered in order so every player receives them in the order they were sent.
+    /// Nothing is delivered while the game is paused, the backlog is flushed again when it's resumed.
+    fn flush_relay_backlog(&mut self, ctx: &mut Context<Self>, room_id: IdType) {
+        let room = match self.rooms.get_mut(&room_id) {
+            Some(x) if !x.paused => x,
+            _ => return,
+        };
+
+        let mut budget = RELAY_FANOUT_SLICE;
+        while let Some(pending) = room.relay_backlog.front_mut() {
+            while budget > 0 {
+                let id = match pending.recipients.pop() {
+                    Some(x) => x,
+                    None => break,
+                };
+                budget -= 1;
+
+                let player = match self.players.get_mut(&id) {
+                    Some(x) => x,
+                    None => continue,
+                };
+                if (player.obj.in_game && player.room == Some(room_id)) || player.spectating == Some(room_id) {
+                    player.deliver(pending.pkt.clone());
+                }
+            }
+
+            if !pending.recipients.is_empty() {
+                break;
+            }
+            room.relay_backlog.pop_front();
+        }
+
+        if !room.relay_backlog.is_empty() {
+            ctx.notify(FlushRelayBacklog { room_id });
+        }
+    }
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|x| x.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// A json relay packet ready to be delivered.
+struct RelayEnvelope {
+    raw: String,
+    ack_id: Option<u64>,// The sender requested delivery receipts.
+    private: bool,// Only for the players, the spectators don't receive it.
+}
+
+/// Prepares a relay packet for the other players, adding the server fields (sender and the optional timestamp).
+/// Returns the error code if the packet isn't a json object or its envelope version isn't supported.
+fn transform_relay_payload(data: &str, sender_id: IdType, config: &Config) -> Result<RelayEnvelope, &'static str> {
+    let mut obj: Map<String, Value> = serde_json::from_str(data).map_err(|_| "invalid_relay_json")?;
+    if let Some(v) = obj.get("v") {
+        // The version is optional for the clients that predate it.
+        if !v.as_u64().is_some_and(|x| SUPPORTED_RELAY_VERSIONS.contains(&x)) {
+            return Err("unsupported_relay_version");
+        }
+    }
+    let ack_id = obj.get("ackId").and_then(Value::as_u64);
+    let private = obj.get("private").and_then(Value::as_bool).unwrap_or(false);
+
+    obj.insert(config.relay_sender_field.clone(), Value::String(SerId(sender_id).to_string()));
+    // The relayed packets have a single server time, whichever option asked for it.
+    if config.relay_timestamp || config.message_timestamps {
+        obj.insert("timestamp".to_string(), Value::from(now_millis()));
+    }
+
+    let raw = serde_json::to_string(&obj).map_err(|_| "invalid_relay_json")?;
+    Ok(RelayEnvelope { raw, ack_id, private })
+}
+
+impl Handler<RegisterSession> for ServerActor {
+    type Result = Option<(IdType, String)>;
+
+    fn handle(&mut self, mut msg: RegisterSession, _: &mut Context<Self>) -> Self::Result {
+        self.assign_default_cosmetics(&mut msg.obj.cosmetics);
+
+        match msg.id {
+            Some(id) => {
+                let player = self.players.get_mut(&id).expect("Invalid player");
+                if player.room.is_some() {
+                    return None;
+                }
+                player.roster_diffs = msg.obj.roster_diffs;
+                player.locale = msg.obj.locale;
+                player.platform = msg.obj.platform;
+                player.obj.username = msg.obj.username;
+                player.obj.cosmetics = msg.obj.cosmetics;
+                Some((id, player.reconnect_token.clone()))
+            },
+            None => {
+                let pobj = PlayerObject {
+                    id: 0.into(),
+                    username: msg.obj.username,
+                    cosmetics: msg.obj.cosmetics,
+                    is_host: false,
+                    in_game: false,
+                };
+                let reconnect_token = base64::encode_config(self.rng.gen::<[u8; 16]>(), base64::URL_SAFE_NO_PAD);
+                let id = self.allocate_player_id(UserData {
+                    addr: msg.addr,
+                    obj: pobj,
+                    room: None,
+                    joined_room_at: Instant::now(),
+                    last_room_create: None,
+                    unresponsive: false,
+                    relay_window_start: Instant::now(),
+                    relay_window_count: 0,
+                    relay_rate_reported: false,
+                    roster_diffs: msg.obj.roster_diffs,
+                    locale: msg.obj.locale,
+                    platform: msg.obj.platform,
+                    last_left: None,
+                    buffered_bytes: 0,
+                    last_activity: Instant::now(),
+                    reconnect_token: reconnect_token.clone(),
+                    disconnected: None,
+                    spectating: None,
+                    join_next_game: false,
+                });
+                self.sessions_by_token.insert(reconnect_token.clone(), id);
+                if let Some(reason) = self.shutting_down {
+                    // Logged in during the shutdown grace time.
+                    self.players[&id].addr.do_send(ServerShutdown { reason });
+                }
+                Some((id, reconnect_token))
+            }
+        }
+
+    }
+}
+
+impl Handler<Shutdown> for ServerActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: Shutdown, ctx: &mut Context<Self>) -> Self::Result {
+        if self.shutting_down.is_some() {
+            return;
+        }
+        self.shutting_down = Some(msg.reason);
+        println!("[Shutdown] Disconnecting {} players ({})", self.players.len(), msg.reason);
+
+        // Last chance for the games to deliver what has been relayed, the notice is queued after the packets.
+        let room_ids: Vec<IdType> = self.rooms.keys().copied().collect();
+        for room_id in room_ids {
+            self.rooms.get_mut(&room_id).unwrap().paused = false;
+            while self.rooms.get(&room_id).is_some_and(|x| !x.relay_backlog.is_empty()) {
+                self.flush_relay_backlog(ctx, room_id);
+            }
+        }
+        for player in self.players.values() {
+            player.addr.do_send(ServerShutdown { reason: msg.reason });
+        }
+    }
+}
+
+impl Handler<GetSessionStatus> for ServerActor {
+    type Result = SessionStatus;
+
+    fn handle(&mut self, msg: GetSessionStatus, _ctx: &mut Context<Self>) -> Self::Result {
+        let player = match self.sessions_by_token.get(&msg.token).and_then(|x| self.players.get(x)) {
+            Some(x) => x,
+            None => return SessionStatus { status: "expired", state: None, room_id: None },
+        };
+        // The same states as the resume.
+        let state = if player.obj.in_game {
+            "playing"
+        } else if player.spectating.is_some() {
+            "spectating"
+        } else if player.room.is_some() {
+            "lobby"
+        } else {
+            "matchmaking"
+        };
+        SessionStatus {
+            status: if player.disconnected.is_some() { "grace" } else { "live" },
+            state: Some(state),
+            room_id: player.room.or(player.spectating).map(SerId),
+        }
+    }
+}
+
+impl Handler<IsShuttingDown> for ServerActor {
+    type Result = bool;
+
+    fn handle(&mut self, _: IsShuttingDown, _ctx: &mut Context<Self>) -> Self::Result {
+        self.shutting_down.is_some()
+    }
+}
+
+impl Handler<Disconnect> for ServerActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: Disconnect, ctx: &mut Context<Self>) -> Self::Result {
+        if !self.players.get(&msg.id).is_some_and(|x| x.addr == msg.addr) {
+            return;// Already gone or reconnected from another connection.
+        }
+        let player = self.players.get_mut(&msg.id).unwrap();
+
+        // Keep the session (and the seat) of the players, they might just be on a flaky connection or reloading the page.
+        let mut grace = if player.obj.in_game { self.config.reconnect_grace_ms } else { self.config.session_resume_ms };
+        if player.platform == Platform::Mobile {
+            // Backgrounding the app drops the connection.
+            grace = grace.max(self.config.mobile_grace_ms);
+        }
+        if grace > 0 {
+            let handle = ctx.notify_later(ReconnectGraceEnded { id: msg.id }, Duration::from_millis(grace));
+            player.disconnected = Some(handle);
+            let in_game = player.obj.in_game;
+            let room_id = player.room;
+            let rooms = &mut self.rooms;
+            if let Some(room) = room_id.and_then(|x| rooms.get_mut(&x)) {
+                room.log(format!("{} disconnected, waiting {}ms for it to reconnect", SerId(msg.id), grace));
+                if in_game {
+                    let event = OutGameEvent::PlayerDisconnected { player: msg.id.into() };
+                    ServerActor::broadcast_game_event(room, &self.players, event, Some(msg.id));
+                }
+                if room.state == RoomState::Playing && room.turn_order.get(room.turn_index) == Some(&msg.id) {
+                    self.advance_turn(room_id.unwrap());
+                }
+            }
+            return;
+        }
+
+        self.stop_spectating(msg.id);
+        self.leave_room_if_any(ctx, msg.id);
+        self.remove_player(msg.id);
+    }
+}
+
+impl Handler<ReconnectGraceEnded> for ServerActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: ReconnectGraceEnded, ctx: &mut Context<Self>) -> Self::Result {
+        let player = match self.players.get_mut(&msg.id) {
+            Some(x) if x.disconnected.is_some() => x,
+            _ => return,
+        };
+        let rooms = &self.rooms;
+        if player.obj.in_game && player.room.and_then(|x| rooms.get(&x)).is_some_and(|x| x.paused) {
+            // Nobody is playing while the game is paused, the grace is extended until it's resumed.
+            let handle = ctx.notify_later(ReconnectGraceEnded { id: msg.id }, Duration::from_millis(self.config.reconnect_grace_ms));
+            player.disconnected = Some(handle);
+            return;
+        }
+        println!("[Reconnect] Player {} hasn't reconnected in time, removing it", msg.id);
+        self.stop_spectating(msg.id);
+        self.leave_room_if_any(ctx, msg.id);
+        self.remove_player(msg.id);
+    }
+}
+
+impl Handler<ReconnectSession> for ServerActor {
+    type Result = Option<ReconnectInfo>;
+
+    fn handle(&mut self, msg: ReconnectSession, ctx: &mut Context<Self>) -> Self::Result {
+        let player = self.players.get_mut(&msg.player_id)?;
+        if player.reconnect_token != msg.token || player.room != Some(msg.room_id) || !player.obj.in_game {
+            return None;
+        }
+
+        match player.disconnected.take() {
+            Some(handle) => {
+                ctx.cancel_future(handle);
+            },
+            None => {
+                // The old connection hasn't noticed that it's dead yet (or it's still alive).
+                player.addr.do_send(SessionReplaced);
+            },
+        }
+        player.addr = msg.addr;
+        player.unresponsive = false;
+        let info = ReconnectInfo {
+            is_host: player.obj.is_host,
+            locale: player.locale,
+        };
+        if let Some(room) = self.rooms.get_mut(&msg.room_id) {
+            room.log(format!("{} reconnected", SerId(msg.player_id)));
+            let event = OutGameEvent::PlayerReconnected { player: msg.player_id.into() };
+            ServerActor::broadcast_game_event(room, &self.players, event, Some(msg.player_id));
+        }
+        println!("[Reconnect] Player {} reconnected", msg.player_id);
+
+        Some(info)
+    }
+}
+
+impl Handler<ResumeSession> for ServerActor {
+    type Result = Option<ResumeInfo>;
+
+    fn handle(&mut self, msg: ResumeSession, ctx: &mut Context<Self>) -> Self::Result {
+        let player_id = *self.sessions_by_token.get(&msg.token)?;
+        let player = self.players.get_mut(&player_id)?;
+
+        match player.disconnected.take() {
+            Some(handle) => {
+                ctx.cancel_future(handle);
+            },
+            None => {
+                // The old connection hasn't noticed that it's dead yet (or it's still alive).
+                player.addr.do_send(SessionReplaced);
+            },
+        }
+        player.addr = msg.addr;
+        player.unresponsive = false;
+        println!("[Resume] Player {} resumed its session", player_id);
+
+        // Spectators get their seat back too, still as spectators.
+        let spectating = player.spectating.is_some();
+        let room_id = player.room.or(player.spectating);
+        let players_by_id = &self.players;
+        let rooms = &mut self.rooms;
+        let in_game = players_by_id[&player_id].obj.in_game;
+        let players = room_id.and_then(|x| rooms.get_mut(&x)).map(|room| {
+            room.log(format!("{} resumed its session", SerId(player_id)));
+            if in_game {
+                let event = OutGameEvent::PlayerReconnected { player: player_id.into() };
+                ServerActor::broadcast_game_event(room, players_by_id, event, Some(player_id));
+            }
+            room.players.iter()
+                .filter_map(|id| players_by_id.get(id))
+                .map(|x| x.obj.clone())
+                .collect()
+        });
+        let player = &self.players[&player_id];
+
+        Some(ResumeInfo {
+            player_id,
+            player: player.obj.clone(),
+            locale: player.locale,
+            in_game: player.obj.in_game,
+            spectating,
+            players,
+        })
+    }
+}
+
+impl Handler<ReportConnectionStats> for ServerActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: ReportConnectionStats, _ctx: &mut Context<Self>) -> Self::Result {
+        if let Some(player) = self.players.get_mut(&msg.id) {
+            player.buffered_bytes = msg.buffered_bytes;
+            player.last_activity = msg.last_activity;
+        }
+    }
+}
+
+impl Handler<FindRoom> for ServerActor {
+    type Result = FindRoomResult;
+
+    fn handle(&mut self, msg: FindRoom, ctx: &mut Context<Self>) -> Self::Result {
+        let my_id = msg.id;
+        if !self.players.contains_key(&my_id) {
+            // The session has been removed while the message was queued.
+            return FindRoomResult::SessionNotFound;
+        }
+
+        // The fullest rooms first so that they start sooner, the empty (warm) ones only if there's no one to play with.
+        let room_id = self.find_available_room(1..=self.config.max_players);
+
+        if let Some(room_id) = room_id {
+            let was_warm = self.warm_rooms.contains(&room_id);
+            if let JoinRoomResult::Success(players, chat_history) = self.join_room(ctx, my_id, room_id) {
+                println!("[FindRoom] Room {} found for player {}.", room_id, my_id);
+                return FindRoomResult::Success {
+                    room_id,
+                    players,
+                    chat_history,
+                    just_created: was_warm,
+                };
+            }
+        }
+
+        if self.is_server_full() {
+            return FindRoomResult::GameIsFull;
+        }
+        let room_id = match self.create_room(my_id, true) {
+            Some(x) => x,
+            None => return FindRoomResult::SessionNotFound,
+        };
+        println!("[FindRoom] Room {} created for player {}.", room_id, my_id);
+
+        FindRoomResult::Success {
+            room_id,
+            players: vec![self.players.get(&my_id).unwrap().obj.clone()],
+            chat_history: Vec::new(),
+            just_created: true,
+        }
+    }
+}
+
+impl Handler<CreateRoom> for ServerActor {
+    type Result = CreateRoomResult;
+
+    fn handle(&mut self, msg: CreateRoom, ctx: &mut Context<Self>) -> Self::Result {
+        let cooldown = Duration::from_millis(self.config.room_create_cooldown_ms);
+        let player = match self.players.get_mut(&msg.id) {
+            Some(x) => x,
+            None => return CreateRoomResult::SessionNotFound,// Removed while the message was queued
+        };
+        if let Some(last) = player.last_room_create {
+            if last.elapsed() < cooldown {
+                return CreateRoomResult::RateLimited;
+            }
+        }
+
+        if self.is_server_full() {
+            return CreateRoomResult::ServerFull;
+        }
+
+        self.leave_room_if_any(ctx, msg.id);
+        let room_id = match self.create_room(msg.id, false) {
+            Some(x) => x,
+            None => return CreateRoomResult::SessionNotFound,
+        };
+        if self.config.private_room_auto_open_ms > 0 {
+            let wait = Duration::from_millis(self.config.private_room_auto_open_ms);
+            let handle = ctx.notify_later(AutoOpenRoom { room_id }, wait);
+            self.rooms.get_mut(&room_id).unwrap().auto_open_handle = Some(handle);
+        }
+        let player = self.players.get_mut(&msg.id).unwrap();
+        player.last_room_create = Some(Instant::now());
+        CreateRoomResult::Success {
+            room_id,
+            player: player.obj.clone()
+        }
+    }
+}
+
+impl Handler<AutoOpenRoom> for ServerActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: AutoOpenRoom, _ctx: &mut Context<Self>) -> Self::Result {
+        let room = match self.rooms.get_mut(&msg.room_id) {
+            Some(x) => x,
+            None => return,
+        };
+        room.auto_open_handle = None;
+        if room.persistent || room.state != RoomState::Matchmaking || room.players.len() >= self.config.min_players {
+            return;
+        }
+
+        room.log("Opened to the matchmaking".to_string());
+        println!("[AutoOpen] Room {} hasn't reached the min players, it's public now.", msg.room_id);
+        let event = OutEvent::EventRoomVisibilityChanged { public: true };
+        ServerActor::broadcast_roster_event(room, &mut self.players, event.clone(), event, None);
+        self.pub_rooms.insert(msg.room_id);
+        self.refresh_room_availability(msg.room_id);
+    }
+}
+
+impl Handler<JoinRoom> for ServerActor {
+    type Result = JoinRoomResult;
+
+    fn handle(&mut self, msg: JoinRoom, ctx: &mut Context<Self>) -> Self::Result {
+        self.join_room(ctx, msg.id, msg.room_id)
+    }
+}
+
+impl Handler<RejoinRoom> for ServerActor {
+    type Result = JoinRoomResult;
+
+    fn handle(&mut self, msg: RejoinRoom, ctx: &mut Context<Self>) -> Self::Result {
+        let room_id = match self.players.get(&msg.id).and_then(|x| x.last_left) {
+            Some((room_id, time)) if time.elapsed() < REJOIN_WINDOW => room_id,
+            _ => return JoinRoomResult::RoomNotFound,
+        };
+        self.join_room(ctx, msg.id, room_id)
+    }
+}
+
+impl Handler<PeekRoom> for ServerActor {
+    type Result = Option<RoomPeekResponse>;
+
+    fn handle(&mut self, msg: PeekRoom, _ctx: &mut Context<Self>) -> Self::Result {
+        let room = self.rooms.get(&msg.room_id)?;
+
+        Some(RoomPeekResponse {
+            players: room.players.iter()
+                .filter_map(|id| self.players.get(id))
+                .map(|x| x.obj.clone())
+                .collect(),
+            player_count: room.players.len(),
+            max_players: self.config.max_players,
+            joinable: room.state == RoomState::Matchmaking && room.players.len() < self.config.max_players,
+            connection_types: self.config.connection_types_for(room.players.len()),
+        })
+    }
+}
+
+impl Handler<SpectateRoom> for ServerActor {
+    type Result = SpectateResult;
+
+    fn handle(&mut self, msg: SpectateRoom, _ctx: &mut Context<Self>) -> Self::Result {
+        let room = match self.rooms.get_mut(&msg.room_id) {
+            Some(x) => x,
+            None => return SpectateResult::RoomNotFound,
+        };
+        if room.state != RoomState::Playing {
+            return SpectateResult::NotPlaying;
+        }
+        let player = match self.players.get_mut(&msg.id) {
+            Some(x) if x.room.is_none() => x,
+            _ => return SpectateResult::RoomNotFound,// Disconnected or still leaving its room.
+        };
+
+        player.spectating = Some(msg.room_id);
+        player.join_next_game = msg.join_next;
+        room.spectators.insert(msg.id);
+        room.log(format!("{} is spectating", SerId(msg.id)));
+
+        let players_by_id = &self.players;
+        let players = room.players.iter()
+            .filter_map(|id| players_by_id.get(id))
+            .map(|x| x.obj.clone())
+            .collect();
+        SpectateResult::Success(players)
+    }
+}
+
+impl Handler<StopSpectating> for ServerActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: StopSpectating, _ctx: &mut Context<Self>) -> Self::Result {
+        self.stop_spectating(msg.id);
+    }
+}
+
+impl Handler<GetRoster> for ServerActor {
+    type Result = Option<Vec<PlayerObject>>;
+
+    fn handle(&mut self, msg: GetRoster, _ctx: &mut Context<Self>) -> Self::Result {
+        let room_id = self.players.get(&msg.id)?.room?;
+        let room = self.rooms.get(&room_id)?;
+
+        Some(room.players.iter()
+            .filter_map(|id| self.players.get(id))
+            .map(|x| x.obj.clone())
+            .collect())
+    }
+}
+
+impl Handler<EditCosmetics> for ServerActor {
+    type Result = bool;
+
+    fn handle(&mut self, msg: EditCosmetics, ctx: &mut Context<Self>) -> Self::Result {
+        let player = match self.players.get_mut(&msg.id) {
+            Some(x) => x,
+            None => return false,// Disconnected while the change was queued.
+        };
+
+        if player.obj.cosmetics == msg.obj {
+            return false;
+        }
+        player.obj.cosmetics = msg.obj;
+
+        let rooms = &self.rooms;
+        if player.room.is_some_and(|x| !rooms.contains_key(&x)) {
+            // Stale reference to a deleted room, the player isn't in any room.
+            player.room = None;
+        }
+        if player.room.is_none() || self.pending_cosmetics.contains(&msg.id) {
+            // Nobody to tell or already waiting, the delayed broadcast will send the latest cosmetics.
+            return true;
+        }
+
+        if self.take_cosmetics_broadcast() {
+            self.broadcast_cosmetics(msg.id);
+        } else {
+            self.pending_cosmetics.insert(msg.id);
+            self.schedule_cosmetics_flush(ctx);
+        }
+        true
+    }
+}
+
+impl Handler<SetReady> for ServerActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetReady, _ctx: &mut Context<Self>) -> Self::Result {
+        let room = match self.players.get(&msg.id) {
+            Some(UserData { room: Some(room_id), .. }) => self.rooms.get_mut(room_id),
+            _ => None,
+        };
+        let room = match room {
+            Some(x) if x.state == RoomState::Matchmaking => x,
+            _ => return,// Left the room or the game has started while the message was queued.
+        };
+        let changed = if msg.ready {
+            room.ready.insert(msg.id)
+        } else {
+            room.ready.remove(&msg.id)
+        };
+        if !changed {
+            return;
+        }
+
+        let event = OutEvent::EventPlayerReady {
+            player: msg.id.into(),
+            ready: msg.ready,
+        };
+        ServerActor::broadcast_roster_event(room, &mut self.players, event.clone(), event, None);
+    }
+}
+
+impl Handler<ChatMessage> for ServerActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: ChatMessage, _ctx: &mut Context<Self>) -> Self::Result {
+        let room = match self.players.get(&msg.sender_id) {
+            Some(UserData { room: Some(room_id), .. }) => self.rooms.get_mut(room_id),
+            _ => None,
+        };
+        let room = match room {
+            Some(x) => x,
+            None => return,// Left the room while the message was queued.
+        };
+        if room.chat_history.len() >= CHAT_HISTORY_SIZE {
+            room.chat_history.pop_front();
+        }
+        room.chat_history.push_back(ChatEntry {
+            player: msg.sender_id.into(),
+            text: msg.text.clone(),
+        });
+        let event = OutEvent::EventChat {
+            player: msg.sender_id.into(),
+            text: msg.text,
+        };
+        ServerActor::broadcast_roster_event(room, &mut self.players, event.clone(), event, Some(msg.sender_id));
+    }
+}
+
+impl Handler<LeaveRoom> for ServerActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: LeaveRoom, ctx: &mut Context<Self>) -> Self::Result {
+        self.leave_room_if_any(ctx, msg.id);
+    }
+}
+
+impl Handler<KickPlayer> for ServerActor {
+    type Result = KickPlayerResult;
+
+    fn handle(&mut self, msg: KickPlayer, ctx: &mut Context<Self>) -> Self::Result {
+        let room_id = match self.players.get(&msg.host_id) {
+            Some(UserData { room: Some(room_id), obj, .. }) if obj.is_host => *room_id,
+            _ => return KickPlayerResult::NotHost,
+        };
+        let room = match self.rooms.get_mut(&room_id) {
+            Some(x) => x,
+            None => return KickPlayerResult::NotHost,
+        };
+        if msg.target_id == msg.host_id || !room.players.contains(&msg.target_id) {
+            return KickPlayerResult::PlayerNotFound;
+        }
+        if room.state != RoomState::Matchmaking {
+            return KickPlayerResult::AlreadyPlaying;
+        }
+        room.log(format!("{} kicked by the host", SerId(msg.target_id)));
+
+        self.leave_room_if_any(ctx, msg.target_id);
+        if let Some(target) = self.players.get_mut(&msg.target_id) {
+            target.last_left = None;// Kicked players can't just rejoin.
+            target.deliver(Event(OutEvent::EventKicked { reason: "host" }));
+        }
+        KickPlayerResult::Success
+    }
+}
+
+impl Handler<StartRoom> for ServerActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: StartRoom, ctx: &mut Context<Self>) -> Self::Result {
+        // The requests are processed in order, if the host has left (or isn't the host anymore) by the time
+        // its start request is handled the start is rejected, the new host can request it again.
+        let player = match self.players.get(&msg.id) {
+            Some(x) => x,
+            None => return,// Disconnected, nobody to tell
+        };
+        let room_id = match player.room {
+            Some(x) if player.obj.is_host => x,
+            _ => {
+                player.addr.do_send(Event(OutEvent::EventRoomStartFailed { reason: "not_host" }));
+                return;
+            },
+        };
+        if let Some(reason) = self.start_failure(room_id, msg.conn_type) {
+            player.addr.do_send(Event(OutEvent::EventRoomStartFailed { reason }));
+            return;
+        }
+
+        self.start_room(ctx, room_id, msg.conn_type, Some(msg.id));
+    }
+}
+
+impl Handler<CountdownEnded> for ServerActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: CountdownEnded, ctx: &mut Context<Self>) -> Self::Result {
+        let game_capacity_full = self.is_game_capacity_full();
+        let mut conn_type = self.config.default_connection_type;
+        if let Some(room) = self.rooms.get_mut(&msg.room_id) {
+            room.start_countdown_handle = None;
+            if game_capacity_full {
+                // Try again later, the players keep waiting in the lobby.
+                room.start_countdown(ctx, msg.room_id, self.config.start_countdown_secs.max(1));
+                return;
+            }
+            if room.in_game_count > 0 && room.game_ended_at.elapsed() < REMATCH_WAIT {
+                // A rematch doesn't kick the ones still finishing the last game, unless they take too long.
+                room.start_countdown(ctx, msg.room_id, self.config.start_countdown_secs.max(1));
+                return;
+            }
+            if !self.config.connection_types_for(room.players.len()).contains(&conn_type) {
+                conn_type = RoomConnectionType::ServerBroadcast;
+            }
+        }
+        self.start_room(ctx, msg.room_id, conn_type, None);
+    }
+}
+
+impl Handler<SendRelayMex> for ServerActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: SendRelayMex, ctx: &mut Context<Self>) -> Self::Result {
+        // TODO: do not clone.
+        // it's better to create a queue with multiple indexes
+        // A B C D E
+        //^     ^   ^
+        //p1    p2  p4
+        //      p3
+        if msg.data.is_empty() {
+            return;
+        }
+
+        let room_id = match self.relay_room_of(msg.sender_id) {
+            Some(x) => x,
+            None => return,
+        };
+
+        let RelayEnvelope { raw, ack_id, private } = match transform_relay_payload(&msg.data, msg.sender_id, &self.config) {
+            Ok(x) => x,
+            Err(err) => {
+                self.players[&msg.sender_id].addr.do_send(ErrorEvent(err));
+                return;
+            },
+        };
+
+        if let Some(ack_id) = ack_id {
+            let key = (msg.sender_id, ack_id);
+            if self.rooms[&room_id].pending_acks.contains_key(&key) {
+                // The id got reused before the old message completed, report the old one as it is.
+                self.finish_relay_ack(ctx, room_id, key);
+            }
+            let missing: HashSet<IdType> = self.rooms[&room_id].players.iter()
+                .filter(|x| **x != msg.sender_id && self.players.get(x).is_some_and(|p| p.obj.in_game))
+                .copied()
+                .collect();
+            let no_recipients = missing.is_empty();
+            let timeout_handle = ctx.run_later(RELAY_ACK_TIMEOUT, move |act, ctx| {
+                act.finish_relay_ack(ctx, room_id, key);
+            });
+            let room = self.rooms.get_mut(&room_id).unwrap();
+            room.pending_acks.insert(key, PendingAck {
+                acked: Vec::new(),
+                missing,
+                timeout_handle,
+            });
+            if no_recipients {
+                self.finish_relay_ack(ctx, room_id, key);
+            }
+        }
+
+        self.enqueue_relay(ctx, room_id, msg.sender_id, RelayData::Text(raw), !private);
+    }
+}
+
+impl Handler<SendRelayBinary> for ServerActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: SendRelayBinary, ctx: &mut Context<Self>) -> Self::Result {
+        if msg.data.is_empty() {
+            return;
+        }
+
+        let room_id = match self.relay_room_of(msg.sender_id) {
+            Some(x) => x,
+            None => return,
+        };
+
+        // The sender is a fixed size header instead of a json field.
+        let mut data = Vec::with_capacity(mem::size_of::<u64>() + msg.data.len());
+        data.extend_from_slice(&(msg.sender_id as u64).to_be_bytes());
+        data.extend_from_slice(&msg.data);
+        self.enqueue_relay(ctx, room_id, msg.sender_id, RelayData::Binary(data.into()), true);
+    }
+}
+
+impl Handler<RelayAck> for ServerActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: RelayAck, ctx: &mut Context<Self>) -> Self::Result {
+        let room_id = match self.players.get(&msg.id).and_then(|x| x.room) {
+            Some(x) => x,
+            None => return,
+        };
+        let room = match self.rooms.get_mut(&room_id) {
+            Some(x) => x,
+            None => return,
+        };
+        let key = (msg.sender, msg.ack_id);
+        let pending = match room.pending_acks.get_mut(&key) {
+            Some(x) => x,
+            None => return,// Unknown or already timed out
+        };
+        if pending.missing.remove(&msg.id) {
+            pending.acked.push(msg.id);
+        }
+        if pending.missing.is_empty() {
+            self.finish_relay_ack(ctx, room_id, key);
+        }
+    }
+}
+
+impl Handler<EndTurn> for ServerActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: EndTurn, _ctx: &mut Context<Self>) -> Self::Result {
+        let player = match self.players.get(&msg.id) {
+            Some(x) => x,
+            None => return,
+        };
+        let room = match player.room.and_then(|x| self.rooms.get(&x)) {
+            Some(x) if player.obj.in_game => x,
+            _ => return,
+        };
+
+        if !room.connection_type.is_relayed() {
+            // The peers keep track of their turns.
+            player.addr.do_send(ErrorEvent("relay_disabled"));
+            return;
+        }
+        if room.paused {
+            player.addr.do_send(ErrorEvent("game_paused"));
+            return;
+        }
+        if room.turn_order.get(room.turn_index) != Some(&msg.id) {
+            player.addr.do_send(ErrorEvent("not_your_turn"));
+            return;
+        }
+
+        let room_id = player.room.unwrap();
+        self.advance_turn(room_id);
+    }
+}
+
+impl Handler<PauseGame> for ServerActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: PauseGame, ctx: &mut Context<Self>) -> Self::Result {
+        let player = match self.players.get(&msg.id) {
+            Some(x) => x,
+            None => return,
+        };
+        let room_id = match player.room {
+            Some(x) if player.obj.in_game => x,
+            _ => return,
+        };
+        let room = match self.rooms.get_mut(&room_id) {
+            Some(x) => x,
+            None => {
+                // Stale reference to a deleted room, there's no game to pause.
+                player.addr.do_send(ErrorEvent("not_in_game"));
+                return;
+            },
+        };
+
+        if !player.obj.is_host {
+            player.addr.do_send(ErrorEvent("not_host"));
+            return;
+        }
+        if !room.connection_type.is_relayed() {
+            // Nothing goes through the server to hold back.
+            player.addr.do_send(ErrorEvent("relay_disabled"));
+            return;
+        }
+        if room.paused == msg.paused {
+            return;
+        }
+        room.paused = msg.paused;
+        room.log(format!("Game {} by {}", if msg.paused { "paused" } else { "resumed" }, msg.id));
+
+        let event = if msg.paused {
+            OutGameEvent::GamePaused { by: msg.id.into() }
+        } else {
+            OutGameEvent::GameResumed { by: msg.id.into() }
+        };
+        for id in room.players.iter() {
+            if let Some(player) = self.players.get(id) {
+                if player.obj.in_game {
+                    player.addr.do_send(GameEvent(event.clone()));
+                }
+            }
+        }
+        if !msg.paused {
+            // Deliver what has been relayed during the pause, after the resume event.
+            self.flush_relay_backlog(ctx, room_id);
+        }
+    }
+}
+
+impl Handler<FlushRelayBacklog> for ServerActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: FlushRelayBacklog, ctx: &mut Context<Self>) -> Self::Result {
+        self.flush_relay_backlog(ctx, msg.room_id);
+    }
+}
+
+impl Handler<GameEndRequest> for ServerActor {
+    type Result = Option<GameEndAck>;
+
+    fn handle(&mut self, msg: GameEndRequest, ctx: &mut Context<Self>) -> Self::Result {
+        let player = self.players.get_mut(&msg.id).expect("Invalid player");
+        let rooms = &mut self.rooms;
+        let room = match player.room.and_then(|x| rooms.get_mut(&x)) {
+            Some(x) => x,
+            None => return None,
+        };
+
+        if !player.obj.in_game {
+            return None;
+        }
+        let was_player_turn = room.turn_order.get(room.turn_index) == Some(&msg.id);
+
+        // The first player back from the game ends it for the whole room.
+        if room.state == RoomState::Playing {
+            if self.completed_games.len() >= COMPLETED_GAMES_SIZE {
+                self.completed_games.pop_front();
+            }
+            self.completed_games.push_back(CompletedGame {
+                room_id: player.room.unwrap().into(),
+                players: room.game_players.iter().map(|x| SerId(*x)).collect(),
+                connection_type: room.connection_type,
+                duration_ms: room.game_started_at.elapsed().as_millis() as u64,
+                ended_at: now_millis(),
+            });
+            // The ones still playing get what has been held by the pause.
+            room.paused = false;
+            room.game_ended_at = Instant::now();
+        }
+
+        room.state = RoomState::Matchmaking;
+        player.obj.in_game = false;
+        room.in_game_count -= 1;
+        room.log(format!("Game ended by {}", player.obj.id));
+
+        let room_id = player.room.unwrap();
+        // The lobby sees it back, it gets the whole roster with the ack.
+        let event = OutEvent::EventPlayerReturned { player: player.obj.id };
+        let diff = OutEvent::EventRosterDiff {
+            added: Vec::new(),
+            removed: Vec::new(),
+            changed: vec![player.obj.clone()],
+        };
+        ServerActor::broadcast_roster_event(room, &mut self.players, event, diff, Some(msg.id));
+        if was_player_turn {
+            // The ones still playing don't wait for it.
+            self.advance_turn(room_id);
+        }
+        let room = self.rooms.get_mut(&room_id).unwrap();
+        if self.config.rematchmaking && self.pub_rooms.contains(&room_id) {
+            // Let other players in and start another game as soon as enough players are back from the last one,
+            // the ones that don't want to play again just leave the room.
+            let ready_count = room.players.len() - room.in_game_count as usize;
+            if ready_count >= self.config.min_players && room.start_countdown_handle.is_none() {
+                room.start_countdown(ctx, room_id, self.config.start_countdown_secs);
+            }
+        }
+        if self.rooms[&room_id].in_game_count == 0 {
+            // The spectators keep watching the ones still playing.
+            self.end_spectating_after_game(ctx, room_id);
+        }
+        self.refresh_room_availability(room_id);
+        self.flush_relay_backlog(ctx, room_id);
+
+        let room = self.rooms.get(&room_id).unwrap();
+
+        let users = room.players.iter()
+            .map(|x| self.players.get(x).expect("Cannot find player").obj.clone())
+            .collect();
+
+        return Some(GameEndAck(users));
+    }
+}
+
+
+impl Handler<ListPlayers> for ServerActor {
+    type Result = AdminPlayerList;
+
+    fn handle(&mut self, msg: ListPlayers, _ctx: &mut Context<Self>) -> Self::Result {
+        let mut ids: Vec<IdType> = match msg.room {
+            Some(room_id) => match self.rooms.get(&room_id) {
+                Some(room) => room.players.iter().copied().collect(),
+                None => vec![],
+            },
+            None => self.players.keys().copied().collect(),
+        };
+        // Sort them so that the pages are stable between requests.
+        ids.sort_unstable();
+
+        let players = ids.iter()
+            .skip(msg.page.saturating_mul(ADMIN_PAGE_SIZE))
+            .take(ADMIN_PAGE_SIZE)
+            .filter_map(|id| self.players.get(id))
+            .map(admin_player_info)
+            .collect();
+
+        AdminPlayerList {
+            total: ids.len(),
+            page: msg.page,
+            players,
+        }
     }
+}
 
-    fn find_available_room_for(&mut self, player_id: IdType, find_if: impl Fn(IdType, &RoomData) -> bool, max_iter: i32) -> Option<IdType> {
-        let mut found = false;
-        let mut found_room_id = 0;
+impl Handler<GetCapacity> for ServerActor {
+    type Result = ServerCapacity;
+
+    fn handle(&mut self, _: GetCapacity, _ctx: &mut Context<Self>) -> Self::Result {
+        ServerCapacity {
+            players: self.players.len(),
+            rooms: self.rooms.len(),
+            max_rooms: Some(self.config.max_rooms).filter(|x| *x > 0),
+            max_players: Some(self.config.max_rooms * self.config.max_players).filter(|x| *x > 0),
+            // Once no room can be created the new players can only fill the free seats of the public rooms.
+            accepting_new: !self.is_server_full() || !self.pub_rooms_available.is_empty(),
+        }
+    }
+}
 
-        let mut iter = 0;
-        for room_id in &self.pub_rooms_available {
-            if max_iter > 0 && iter >= max_iter {
-                break;
-            }
-            let room_data = self.rooms.get(&room_id).unwrap();
-            if find_if(*room_id, room_data) {
-                found = true;
-                found_room_id = *room_id;
-            }
-            iter += 1;
+impl Handler<GetStats> for ServerActor {
+    type Result = ServerStats;
+
+    fn handle(&mut self, _: GetStats, _ctx: &mut Context<Self>) -> Self::Result {
+        let playing_rooms = self.playing_room_count();
+
+        ServerStats {
+            players: self.players.len(),
+            rooms: self.rooms.len(),
+            available_rooms: self.pub_rooms_available.len(),
+            playing_rooms,
+            matchmaking_rooms: self.rooms.len() - playing_rooms,
         }
+    }
+}
 
-        if found {
-            self.rooms.get_mut(&found_room_id).unwrap().players.insert(player_id);
-            Some(found_room_id)
-        } else {
-            None
+impl Handler<BroadcastAnnouncement> for ServerActor {
+    type Result = usize;
+
+    fn handle(&mut self, msg: BroadcastAnnouncement, _ctx: &mut Context<Self>) -> Self::Result {
+        println!("[Announcement] {}", msg.text);
+        for player in self.players.values_mut() {
+            let text = msg.translations.get(&player.locale).unwrap_or(&msg.text);
+            player.deliver(Event(OutEvent::EventAnnouncement { text: text.clone() }));
         }
+        self.players.len()
     }
 }
 
-impl Handler<RegisterSession> for ServerActor {
-    type Result = IdType;
+impl Handler<CreatePersistentRoom> for ServerActor {
+    type Result = bool;
 
-    fn handle(&mut self, msg: RegisterSession, _: &mut Context<Self>) -> Self::Result {
-        match msg.id {
-            Some(id) => {
-                let player = self.players.get_mut(&id).expect("Invalid player");
-                if player.room.is_none() {
-                    player.obj.username = msg.obj.username;
-                    player.obj.cosmetics = msg.obj.cosmetics;
-                }
-                id
-            },
-            None => {
-                let pobj = PlayerObject {
-                    id: 0.into(),
-                    username: msg.obj.username,
-                    cosmetics: msg.obj.cosmetics,
-                    is_host: false
-                };
-                self.allocate_player_id(UserData {
-                    addr: msg.addr,
-                    obj: pobj,
-                    room: None,
-                    in_game: false,
-                })
-            }
+    fn handle(&mut self, msg: CreatePersistentRoom, _ctx: &mut Context<Self>) -> Self::Result {
+        if self.rooms.contains_key(&msg.room_id) {
+            return false;
         }
+        let mut room = RoomData::new(HashSet::new());
+        room.persistent = true;
+        room.log("Created by the admins (persistent)".to_string());
+        self.rooms.insert(msg.room_id, room);
+        println!("[Admin] Persistent room {} created", SerId(msg.room_id));
+        true
+    }
+}
+
+impl Handler<DumpRoom> for ServerActor {
+    type Result = Option<AdminRoomDump>;
 
+    fn handle(&mut self, msg: DumpRoom, _ctx: &mut Context<Self>) -> Self::Result {
+        let room = self.rooms.get(&msg.room_id)?;
+
+        Some(AdminRoomDump {
+            id: msg.room_id.into(),
+            state: room.state.as_str(),
+            public: self.pub_rooms.contains(&msg.room_id),
+            persistent: room.persistent,
+            players: room.players.iter()
+                .filter_map(|id| self.players.get(id))
+                .map(admin_player_info)
+                .collect(),
+            relay_count: room.relay_count,
+            log: room.log.iter().cloned().collect(),
+        })
     }
 }
 
-impl Handler<Disconnect> for ServerActor {
-    type Result = ();
+impl Handler<ListCompletedGames> for ServerActor {
+    type Result = CompletedGameList;
 
-    fn handle(&mut self, msg: Disconnect, ctx: &mut Context<Self>) -> Self::Result {
-        self.leave_room_if_any(ctx, msg.id);
-        self.players.remove(&msg.id);
+    fn handle(&mut self, _: ListCompletedGames, _ctx: &mut Context<Self>) -> Self::Result {
+        CompletedGameList {
+            games: self.completed_games.iter().rev().cloned().collect(),
+        }
     }
 }
 
-impl Handler<FindRoom> for ServerActor {
-    type Result = FindRoomResult;
+fn admin_player_info(player: &UserData) -> AdminPlayerInfo {
+    AdminPlayerInfo {
+        id: player.obj.id,
+        username: player.obj.username.clone(),
+        room: player.room.map(SerId),
+        in_game: player.obj.in_game,
+        buffered_bytes: player.buffered_bytes,
+        idle_secs: player.last_activity.elapsed().as_secs(),
+    }
+}
 
-    fn handle(&mut self, msg: FindRoom, ctx: &mut Context<Self>) -> Self::Result {
-        let my_id = msg.id;
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
 
-        let mut just_created = false;
+    use crate::protocol::InviteId;
+    use crate::test_client::{settle, start_game, Frame, TestClient};
 
-        let room_id = self.find_available_room_for(
-            my_id, 
-            |_, _| { true }, 
-            -1
-        );
+    use super::*;
 
-        let room_id = match room_id {
-            Some(room_id) => {
-                ctx.notify(JoinRoom { id: my_id, room_id });
-                room_id
-            },
-            None => {
-                just_created = true;
-                self.create_room(my_id, true)
-            }
-        };
-        
-        println!("[FindRoom] Room {} found for player {}.", room_id, my_id);
+    fn start_server(config: Config) -> (Addr<ServerActor>, Arc<Config>) {
+        let config = Arc::new(config);
+        (ServerActor::new(config.clone()).start(), config)
+    }
 
-        FindRoomResult::Success {
-            room_id,
-            players: self.rooms.get(&room_id)
-                .unwrap()
-                .players
-                .iter()
-                .map(|x| self.players.get(x).expect("Cannot find player").obj.clone())
-                .collect(),
-            just_created
+    fn two_players() -> Config {
+        Config {
+            min_players: 2,
+            ..Config::default()
         }
     }
-}
 
-impl Handler<CreateRoom> for ServerActor {
-    type Result = CreateRoomResult;
+    /// Runs the function on the server's state, to reach the states that the clients can't cause.
+    struct Inspect<F>(F);
 
-    fn handle(&mut self, msg: CreateRoom, ctx: &mut Context<Self>) -> Self::Result {
-        self.leave_room_if_any(ctx, msg.id);
-        let room_id = self.create_room(msg.id, false);
-        let player = self.players.get_mut(&msg.id).expect("Cannot find player");
-        CreateRoomResult {
-            room_id,
-            player: player.obj.clone()
-        }
+    impl<F: FnOnce(&mut ServerActor, &mut Context<ServerActor>) -> bool + 'static> Message for Inspect<F> {
+        type Result = bool;
     }
-}
 
-impl Handler<JoinRoom> for ServerActor {
-    type Result = JoinRoomResult;
+    impl<F: FnOnce(&mut ServerActor, &mut Context<ServerActor>) -> bool + 'static> Handler<Inspect<F>> for ServerActor {
+        type Result = bool;
 
-    fn handle(&mut self, msg: JoinRoom, ctx: &mut Context<Self>) -> Self::Result {
+        fn handle(&mut self, msg: Inspect<F>, ctx: &mut Context<Self>) -> Self::Result {
+            (msg.0)(self, ctx)
+        }
+    }
 
-        let my_id = msg.id;
-        let room_id = msg.room_id;
+    /// Returns the reconnect token of the guest.
+    async fn join(client: &mut TestClient, username: &str, invite_id: &str) -> String {
+        let login = client.login(username).await;
+        let res = client.call("room_join", json!({ "inviteId": invite_id })).await;
+        assert_eq!(res["result"], "ok", "{}", res);
+        login["reconnectToken"].as_str().unwrap().to_string()
+    }
 
-        self.leave_room_if_any(ctx, my_id);
+    #[actix_rt::test]
+    async fn peers_see_the_disconnect_and_the_reconnect() {
+        let (db, config) = start_server(two_players());
+        let mut host = TestClient::connect(&db, &config);
+        let invite_id = host.create_room("host").await;
+        let mut guest = TestClient::connect(&db, &config);
+        let token = join(&mut guest, "guest", &invite_id).await;
+        start_game(&mut [&mut host, &mut guest], "server_broadcast").await;
+
+        guest.disconnect();
+        let event = host.recv_type("player_disconnected").await;
+
+        let mut guest = TestClient::connect(&db, &config);
+        let res = guest.call("resume", json!({ "token": token })).await;
+        assert_eq!(res["result"], "ok", "{}", res);
+        let reconnected = host.recv_type("player_reconnected").await;
+        assert_eq!(reconnected["player"], event["player"]);
+        assert_eq!(event["player"], res["playerId"]);
+    }
 
-        let players_by_id = &mut self.players;
+    #[actix_rt::test]
+    async fn spectators_resume_as_spectators() {
+        let (db, config) = start_server(two_players());
+        let mut host = TestClient::connect(&db, &config);
+        let invite_id = host.create_room("host").await;
+        let mut guest = TestClient::connect(&db, &config);
+        join(&mut guest, "guest", &invite_id).await;
+        let start = start_game(&mut [&mut host, &mut guest], "server_broadcast").await;
+
+        let mut spectator = TestClient::connect(&db, &config);
+        let token = spectator.login("spectator").await["reconnectToken"].clone();
+        let res = spectator.call("spectate_join", json!({ "roomId": start["broadcastId"] })).await;
+        assert_eq!(res["result"], "ok", "{}", res);
+        spectator.disconnect();
+        settle().await;
+
+        let mut spectator = TestClient::connect(&db, &config);
+        let res = spectator.call("resume", json!({ "token": token })).await;
+        assert_eq!(res["result"], "ok", "{}", res);
+        assert_eq!(res["state"], "spectating");
+        assert_eq!(res["player"]["inGame"], false);
+        assert_eq!(res["players"].as_array().unwrap().len(), 2);
+
+        host.send_text(r#"{"move": 1}"#);
+        assert_eq!(spectator.recv().await["move"], 1);
+        let res = spectator.call("capabilities", json!({})).await;
+        assert_eq!(res["state"], "spectating");
+    }
 
-        let room_data = match self.rooms.get_mut(&room_id) {
-            Some(room_data) => room_data,
-            None => return JoinRoomResult::RoomNotFound
-        };
+    #[actix_rt::test]
+    async fn mobile_players_keep_their_seat_longer() {
+        let (db, config) = start_server(Config {
+            reconnect_grace_ms: 50,
+            mobile_grace_ms: 1_000,
+            ..two_players()
+        });
+        let mut host = TestClient::connect(&db, &config);
+        let invite_id = host.create_room("host").await;
+        let mut desktop = TestClient::connect(&db, &config);
+        let desktop_token = join(&mut desktop, "desktop", &invite_id).await;
+        let mut mobile = TestClient::connect(&db, &config);
+        let login = mobile.call("login", json!({ "details": { "username": "mobile", "platform": "mobile" } })).await;
+        mobile.call("room_join", json!({ "inviteId": invite_id })).await;
+        start_game(&mut [&mut host, &mut desktop, &mut mobile], "server_broadcast").await;
+
+        desktop.disconnect();
+        mobile.disconnect();
+        actix_rt::time::delay_for(Duration::from_millis(200)).await;
+        let left: Vec<_> = host.drain().into_iter().filter(|x| x["type"] == "player_left").collect();
+        assert_eq!(left.len(), 1);
+        assert_ne!(left[0]["player"], login["playerId"]);
+
+        let mut desktop = TestClient::connect(&db, &config);
+        assert_eq!(desktop.call("resume", json!({ "token": desktop_token })).await["result"], "session_not_found");
+        let mut mobile = TestClient::connect(&db, &config);
+        let res = mobile.call("resume", json!({ "token": login["reconnectToken"] })).await;
+        assert_eq!(res["state"], "playing");
+    }
 
-        if room_data.state != RoomState::Matchmaking {
-            return JoinRoomResult::AlreadyPlaying;
+    #[actix_rt::test]
+    async fn peer_to_peer_is_limited_by_the_room_size() {
+        let (db, config) = start_server(Config {
+            max_peer_to_peer_players: 2,
+            ..two_players()
+        });
+        let mut host = TestClient::connect(&db, &config);
+        let invite_id = host.create_room("host").await;
+        let mut guest = TestClient::connect(&db, &config);
+        join(&mut guest, "guest", &invite_id).await;
+
+        let mut peeker = TestClient::connect(&db, &config);
+        peeker.login("peeker").await;
+        let res = peeker.call("room_peek", json!({ "inviteId": invite_id })).await;
+        assert_eq!(res["connectionTypes"], json!(["server_broadcast", "peer_to_peer", "relay"]));
+
+        let mut third = TestClient::connect(&db, &config);
+        join(&mut third, "third", &invite_id).await;
+        let res = peeker.call("room_peek", json!({ "inviteId": invite_id })).await;
+        assert_eq!(res["connectionTypes"], json!(["server_broadcast", "relay"]));
+
+        host.request("room_start", json!({ "connectionType": "peer_to_peer" }));
+        let event = host.recv_type("event_room_start_failed").await;
+        assert_eq!(event["reason"], "connection_type_unavailable");
+    }
+
+    #[actix_rt::test]
+    async fn turns_skip_the_missing_players() {
+        let (db, config) = start_server(Config {
+            reconnect_grace_ms: 1_000,
+            ..two_players()
+        });
+        let mut clients = vec![];
+        let mut invite_id = String::new();
+        for name in ["a", "b", "c"] {
+            let mut client = TestClient::connect(&db, &config);
+            let id = client.login(name).await["playerId"].clone();
+            if invite_id.is_empty() {
+                invite_id = client.call("room_create", json!({})).await["inviteId"].as_str().unwrap().to_string();
+            } else {
+                client.call("room_join", json!({ "inviteId": invite_id })).await;
+            }
+            clients.push((id, client));
         }
+        let mut refs: Vec<&mut TestClient> = clients.iter_mut().map(|x| &mut x.1).collect();
+        let start = start_game(&mut refs, "server_broadcast").await;
+        let order: Vec<usize> = start["turnOrder"].as_array().unwrap().iter()
+            .map(|id| clients.iter().position(|x| &x.0 == id).unwrap())
+            .collect();
+        let (first, second, third) = (order[0], order[1], order[2]);
 
-        if room_data.players.len() >= MAX_PLAYERS_PER_ROOM {
-            return JoinRoomResult::RoomIsFull;
+        clients[second].1.send_special(json!({ "type": "end_turn" }));
+        assert_eq!(clients[second].1.recv_type("error").await["error"], "not_your_turn");
+
+        clients[first].1.send_special(json!({ "type": "end_turn" }));
+        let second_id = clients[second].0.clone();
+        for (_, client) in clients.iter_mut() {
+            let event = client.recv_type("turn_started").await;
+            assert_eq!(event["player"], second_id);
+            assert_eq!(event["turnNumber"], 1);
         }
 
-        room_data.players.insert(my_id);
-        
-        let user_data = players_by_id.get_mut(&my_id).unwrap();
-        user_data.room = Some(room_id);
+        // The third seat is kept for its reconnection but its turn is skipped.
+        clients[third].1.disconnect();
+        settle().await;
+        clients[second].1.send_special(json!({ "type": "end_turn" }));
+        let event = clients[second].1.recv_type("turn_started").await;
+        assert_eq!(event["player"], clients[first].0);
+        assert_eq!(event["turnNumber"], 2);
+
+        // Ending the game on its own turn passes it.
+        clients[first].1.send_special(json!({ "type": "end_game" }));
+        let event = clients[second].1.recv_type("turn_started").await;
+        assert_eq!(event["player"], clients[second].0);
+        assert_eq!(event["turnNumber"], 3);
+    }
 
-        let player = user_data.obj.clone();
-        ServerActor::broadcast_event_room(
-            &room_data, 
-            players_by_id, 
-            OutEvent::EventPlayerJoined { player }, 
-            None
-        );
-        
-        println!("[JoinRoom] Room {} joined by the player {}.", room_id, my_id);
-        
-        if room_data.players.len() == MIN_PLAYERS_PER_ROOM {
-            let spawn_handle = ctx.notify_later(StartRoom {
-                id: my_id,
-                conn_type: RoomConnectionType::ServerBroadcast
-            }, Duration::from_secs(ROOM_COUNTDOWN_ON_MIN_PLAYERS));
-            room_data.start_countdown_handle = Some(spawn_handle);
+    #[actix_rt::test]
+    async fn the_lobby_sees_the_players_back_from_the_game() {
+        let (db, config) = start_server(two_players());
+        let mut host = TestClient::connect(&db, &config);
+        let invite_id = host.create_room("host").await;
+        let mut guest = TestClient::connect(&db, &config);
+        let guest_id = guest.login("guest").await["playerId"].clone();
+        guest.call("room_join", json!({ "inviteId": invite_id })).await;
+        start_game(&mut [&mut host, &mut guest], "server_broadcast").await;
+
+        host.send_special(json!({ "type": "end_game" }));
+        let ack = host.recv_type("end_game_ack").await;
+        let roster = ack["players"].as_array().unwrap();
+        assert_eq!(roster.iter().find(|x| x["id"] == guest_id).unwrap()["inGame"], true);
+
+        guest.send_special(json!({ "type": "end_game" }));
+        let event = host.recv_type("event_player_returned").await;
+        assert_eq!(event["player"], guest_id);
+    }
 
-            println!("[JoinRoom] Room {} has reached the min players ({}), it's going to start in {} seconds.", room_id, MIN_PLAYERS_PER_ROOM, ROOM_COUNTDOWN_ON_MIN_PLAYERS);
+    #[actix_rt::test]
+    async fn rematches_wait_for_the_players_still_playing() {
+        let (db, config) = start_server(Config {
+            rematchmaking: true,
+            start_countdown_secs: 1,
+            private_room_auto_open_ms: 10,
+            ..two_players()
+        });
+        let mut host = TestClient::connect(&db, &config);
+        let invite_id = host.create_room("host").await;
+        settle().await;// Opened to the matchmaking.
+        let mut guest = TestClient::connect(&db, &config);
+        join(&mut guest, "guest", &invite_id).await;
+        let mut late = TestClient::connect(&db, &config);
+        join(&mut late, "late", &invite_id).await;
+        for client in [&mut host, &mut guest, &mut late] {
+            client.ack_start().await;
         }
 
-        // If the max players are reached the room isn't available anymore (applies only if public).
-        if room_data.players.len() == MAX_PLAYERS_PER_ROOM /*&& self.pub_rooms.contains(&room_id)*/ {
-            self.pub_rooms_available.remove(&room_id);
-        }
+        host.send_special(json!({ "type": "end_game" }));
+        guest.send_special(json!({ "type": "end_game" }));
+        actix_rt::time::delay_for(Duration::from_millis(1_200)).await;
+        let messages = late.drain();
+        assert!(messages.iter().all(|x| x["type"] != "event_kicked" && x["type"] != "event_room_start"), "{:?}", messages);
+        assert!(host.drain().iter().all(|x| x["type"] != "event_room_start" && x["type"] != "event_player_left"));
 
-        JoinRoomResult::Success(
-            room_data.players.iter().map(|id| players_by_id.get(id).unwrap().obj.clone()).collect()
-        )
+        late.send_special(json!({ "type": "end_game" }));
+        for client in [&mut host, &mut guest, &mut late] {
+            assert_eq!(client.ack_start().await["turnOrder"].as_array().unwrap().len(), 3);
+        }
     }
-}
 
-impl Handler<EditCosmetics> for ServerActor {
-    type Result = ();
+    #[actix_rt::test]
+    async fn spectators_watch_until_the_last_player_is_back() {
+        let (db, config) = start_server(two_players());
+        let mut host = TestClient::connect(&db, &config);
+        let invite_id = host.create_room("host").await;
+        let mut guest = TestClient::connect(&db, &config);
+        join(&mut guest, "guest", &invite_id).await;
+        let start = start_game(&mut [&mut host, &mut guest], "server_broadcast").await;
+        let mut spectator = TestClient::connect(&db, &config);
+        spectator.login("spectator").await;
+        spectator.call("spectate_join", json!({ "roomId": start["broadcastId"] })).await;
+
+        host.send_special(json!({ "type": "end_game" }));
+        host.recv_type("end_game_ack").await;
+        guest.send_text(r#"{"board": 1}"#);
+        assert_eq!(spectator.recv().await["board"], 1);
+
+        guest.send_special(json!({ "type": "end_game" }));
+        let event = spectator.recv_type("event_spectate_ended").await;
+        assert!(event["players"].is_null());
+        let res = spectator.call("capabilities", json!({})).await;
+        assert_eq!(res["state"], "matchmaking");
+    }
 
-    fn handle(&mut self, msg: EditCosmetics, _: &mut Context<Self>) -> Self::Result {
-        let player = self.players.get_mut(&msg.id).expect("Invalid player");
+    #[actix_rt::test]
+    async fn room_starts_are_spread_over_the_ticks() {
+        let (db, config) = start_server(Config {
+            max_room_starts_per_tick: 1,
+            ..two_players()
+        });
+        let mut rooms = vec![];
+        for i in 0..3 {
+            let mut host = TestClient::connect(&db, &config);
+            let invite_id = host.create_room(&format!("host{}", i)).await;
+            let mut guest = TestClient::connect(&db, &config);
+            join(&mut guest, &format!("guest{}", i), &invite_id).await;
+            rooms.push((host, guest));
+        }
 
-        if player.obj.cosmetics == msg.obj {
-            return;
+        for (host, _) in rooms.iter_mut() {
+            host.request("room_start", json!({ "connectionType": "server_broadcast" }));
+        }
+        settle().await;
+        // A tick boundary can be crossed once while waiting, never twice.
+        let started = rooms.iter_mut()
+            .map(|(host, _)| host.drain())
+            .filter(|messages| messages.iter().any(|x| x["type"] == "event_room_start"))
+            .count();
+        assert!(started < 3, "{} rooms started at once", started);
+
+        for (_, guest) in rooms.iter_mut() {
+            guest.recv_type("event_room_start").await;
         }
-        player.obj.cosmetics = msg.obj.clone();
+    }
 
-        let room = match player.room {
-            Some(x) => x,
-            None => return,
-        };
+    #[actix_rt::test]
+    async fn players_that_dont_ack_the_start_are_kicked() {
+        let (db, config) = start_server(Config {
+            start_ack_timeout_ms: 50,
+            ..two_players()
+        });
+        let mut host = TestClient::connect(&db, &config);
+        let invite_id = host.create_room("host").await;
+        let mut guest = TestClient::connect(&db, &config);
+        join(&mut guest, "guest", &invite_id).await;
+        let mut stuck = TestClient::connect(&db, &config);
+        let stuck_id = stuck.login("stuck").await["playerId"].clone();
+        stuck.call("room_join", json!({ "inviteId": invite_id })).await;
+
+        start_game(&mut [&mut host, &mut guest], "server_broadcast").await;
+        let kicked = stuck.recv_type("event_kicked").await;
+        assert_eq!(kicked["reason"], "start_ack_timeout");
+        let left = host.recv_type("player_left").await;
+        assert_eq!(left["player"], stuck_id);
+
+        host.send_text(r#"{"board": 1}"#);
+        assert_eq!(guest.recv_type("player_left").await["player"], stuck_id);
+        settle().await;
+        assert!(guest.drain().iter().any(|x| x["board"] == 1));
+    }
 
-        let id = player.obj.id;
+    #[actix_rt::test]
+    async fn starts_wait_for_the_lobby_dwell() {
+        let (db, config) = start_server(Config {
+            min_lobby_dwell_ms: 100,
+            ..two_players()
+        });
+        let mut host = TestClient::connect(&db, &config);
+        let invite_id = host.create_room("host").await;
+        let mut guest = TestClient::connect(&db, &config);
+        join(&mut guest, "guest", &invite_id).await;
+
+        host.request("room_start", json!({ "connectionType": "server_broadcast" }));
+        assert_eq!(host.recv_type("event_room_start_failed").await["reason"], "too_early");
+
+        actix_rt::time::delay_for(Duration::from_millis(100)).await;
+        start_game(&mut [&mut host, &mut guest], "server_broadcast").await;
+    }
 
-        ServerActor::broadcast_event(self.rooms.get(&room).unwrap(), &self.players, OutEvent::EventPlayerAvatarChange {
-            player: id.into(),
-            cosmetics: msg.obj,
-        }, Some(msg.id));
+    #[actix_rt::test]
+    async fn starts_wait_for_a_free_game_slot() {
+        let (db, config) = start_server(Config {
+            max_games: 1,
+            start_countdown_secs: 0,
+            ..two_players()
+        });
+        let mut first_host = TestClient::connect(&db, &config);
+        let invite_id = first_host.create_room("first_host").await;
+        let mut first_guest = TestClient::connect(&db, &config);
+        join(&mut first_guest, "first_guest", &invite_id).await;
+        first_host.ack_start().await;
+        first_guest.ack_start().await;
+
+        // The countdown ends while the only game slot is taken.
+        let mut host = TestClient::connect(&db, &config);
+        let invite_id = host.create_room("host").await;
+        let mut guest = TestClient::connect(&db, &config);
+        join(&mut guest, "guest", &invite_id).await;
+        host.request("room_start", json!({ "connectionType": "server_broadcast" }));
+        assert_eq!(host.recv_type("event_room_start_failed").await["reason"], "server_game_capacity");
+        assert!(guest.drain().iter().all(|x| x["type"] != "event_room_start"));
+
+        // The game ends, the re-armed countdown starts the room.
+        first_host.send_special(json!({ "type": "end_game" }));
+        first_guest.send_special(json!({ "type": "end_game" }));
+        host.ack_start().await;
+        guest.ack_start().await;
     }
-}
 
-impl Handler<LeaveRoom> for ServerActor {
-    type Result = ();
+    #[actix_rt::test]
+    async fn same_cosmetics_are_acked_but_not_broadcast() {
+        let (db, config) = start_server(two_players());
+        let mut host = TestClient::connect(&db, &config);
+        let invite_id = host.create_room("host").await;
+        let mut guest = TestClient::connect(&db, &config);
+        join(&mut guest, "guest", &invite_id).await;
+        let res = guest.call("change_avatar", json!({ "avatar": 1, "color": 2 })).await;
+        assert_eq!(res["changed"], true);
+        assert_eq!(host.recv_type("event_player_avatar_change").await["color"], 2);
+
+        let res = guest.call("change_avatar", json!({ "avatar": 1, "color": 2 })).await;
+        assert_eq!(res["result"], "ok");
+        assert_eq!(res["changed"], false);
+        settle().await;
+        assert!(host.drain().iter().all(|x| x["type"] != "event_player_avatar_change"));
+    }
 
-    fn handle(&mut self, msg: LeaveRoom, ctx: &mut Context<Self>) -> Self::Result {
-        self.leave_room_if_any(ctx, msg.id);
+    #[actix_rt::test]
+    async fn cosmetics_clear_a_deleted_room() {
+        let (db, config) = start_server(Config::default());
+        let mut host = TestClient::connect(&db, &config);
+        host.create_room("host").await;
+        let deleted = db.send(Inspect(|server: &mut ServerActor, _: &mut Context<ServerActor>| {
+            let room_id = server.players.values().find(|x| x.obj.username == "host").and_then(|x| x.room);
+            room_id.and_then(|x| server.rooms.remove(&x)).is_some()
+        })).await.unwrap();
+        assert!(deleted);
+
+        let res = host.call("change_avatar", json!({ "avatar": 1, "color": 2 })).await;
+        assert_eq!(res["result"], "ok", "{}", res);
+        let cleared = db.send(Inspect(|server: &mut ServerActor, _: &mut Context<ServerActor>| {
+            server.players.values().any(|x| x.obj.username == "host" && x.room.is_none())
+        })).await.unwrap();
+        assert!(cleared);
     }
-}
 
-impl Handler<StartRoom> for ServerActor {
-    type Result = ();
+    #[actix_rt::test]
+    async fn large_relays_are_sliced_in_order() {
+        let (db, config) = start_server(two_players());
+        let mut host = TestClient::connect(&db, &config);
+        let invite_id = host.create_room("host").await;
+        let mut guest = TestClient::connect(&db, &config);
+        join(&mut guest, "guest", &invite_id).await;
+        let start = start_game(&mut [&mut host, &mut guest], "server_broadcast").await;
+        let mut recipients = vec![guest];
+        for i in 0..RELAY_FANOUT_SLICE {
+            let mut spectator = TestClient::connect(&db, &config);
+            spectator.login(&format!("spectator{}", i)).await;
+            spectator.call("spectate_join", json!({ "roomId": start["broadcastId"] })).await;
+            recipients.push(spectator);
+        }
 
-    fn handle(&mut self, msg: StartRoom, ctx: &mut Context<Self>) -> Self::Result {
+        let sliced = db.send(Inspect(|server: &mut ServerActor, ctx: &mut Context<ServerActor>| {
+            let host_id = *server.players.iter().find(|(_, x)| x.obj.username == "host").unwrap().0;
+            for data in &[r#"{"n": 1}"#, r#"{"n": 2}"#] {
+                server.handle(SendRelayMex { sender_id: host_id, data: data.to_string() }, ctx);
+            }
+            // The rest waits for the next flushes.
+            server.rooms.values().any(|x| !x.relay_backlog.is_empty())
+        })).await.unwrap();
+        assert!(sliced);
+
+        for client in recipients.iter_mut() {
+            let mut received = vec![];
+            while received.len() < 2 {
+                let mex = client.recv().await;
+                if !mex["n"].is_null() {
+                    received.push(mex["n"].clone());
+                }
+            }
+            assert_eq!(received, vec![1, 2]);
+        }
+    }
 
-        let room_id = match self.players.get(&msg.id).and_then(|x| x.room) {
-            Some(x) => x,
-            None => return,
-        };
+    #[actix_rt::test]
+    async fn default_cosmetics_are_randomized() {
+        let (db, config) = start_server(Config {
+            auto_cosmetics: true,
+            avatar_count: 5,
+            ..Config::default()
+        });
+        let mut client = TestClient::connect(&db, &config);
+        client.login("a").await;
+        let res = client.call("room_create", json!({})).await;
+        let player = &res["players"][0];
+        assert!(player["avatar"].as_u64().unwrap() < 5, "{}", player);
+        assert!((1..=MAX_COLOR).contains(&player["color"].as_u64().unwrap()), "{}", player);
+
+        // Chosen cosmetics are kept.
+        let mut client = TestClient::connect(&db, &config);
+        client.call("login", json!({ "details": { "username": "b", "avatar": 0, "color": 0x00FF00 } })).await;
+        let res = client.call("room_create", json!({})).await;
+        assert_eq!(res["players"][0]["avatar"], 0);
+        assert_eq!(res["players"][0]["color"], 0x00FF00);
+    }
 
-        println!("[StartRoom] Room {} is starting.", room_id);
+    #[actix_rt::test]
+    async fn room_availability_follows_the_room() {
+        let (db, config) = start_server(Config {
+            max_players: 3,
+            rematchmaking: true,
+            start_countdown_secs: 60,
+            ..two_players()
+        });
+        let available = || async {
+            db.send(Inspect(|server: &mut ServerActor, _: &mut Context<ServerActor>| {
+                !server.pub_rooms_available.is_empty()
+            })).await.unwrap()
+        };
+        let mut clients = vec![];
+        for name in ["a", "b", "c"] {
+            let mut client = TestClient::connect(&db, &config);
+            client.login(name).await;
+            assert_eq!(client.call("room_find", json!({})).await["result"], "ok");
+            clients.push(client);
+        }
+        assert!(!available().await);// Full
 
-        if let Some(room) = self.rooms.get_mut(&room_id) {
+        let mut left = clients.pop().unwrap();
+        left.call("room_leave", json!({})).await;
+        assert!(available().await);
 
-            // Ensures that there wasn't any "lobby" countdown running.
-            room.cancel_start_countdown(ctx);
+        let (host, guest) = clients.split_at_mut(1);
+        start_game(&mut [&mut host[0], &mut guest[0]], "server_broadcast").await;
+        assert!(!available().await);
 
-            // Removes the room from the pub rooms available since it has started (shouldn't be applied to private rooms).
-            //if self.pub_rooms.contains(&room_id) {
-                self.pub_rooms_available.remove(&room_id);
-            //}
+        for client in clients.iter_mut() {
+            client.send_special(json!({ "type": "end_game" }));
+            client.recv_type("end_game_ack").await;
+        }
+        assert!(available().await);
+    }
 
-            if room.state != RoomState::Matchmaking || room.players.len() < 2 {
-                return
-            }
+    #[actix_rt::test]
+    async fn cosmetics_broadcasts_are_rate_limited() {
+        let (db, config) = start_server(Config {
+            cosmetics_broadcast_rate: 2,
+            ..two_players()
+        });
+        let mut host = TestClient::connect(&db, &config);
+        let invite_id = host.create_room("host").await;
+        let mut guests = vec![];
+        for i in 0..4 {
+            let mut guest = TestClient::connect(&db, &config);
+            join(&mut guest, &format!("guest{}", i), &invite_id).await;
+            guests.push(guest);
+        }
+        settle().await;
+        host.drain();
 
-            room.state = RoomState::Playing;
+        for (i, guest) in guests.iter_mut().enumerate() {
+            guest.call("change_avatar", json!({ "avatar": 1, "color": i + 1 })).await;
+        }
+        let mut colors: Vec<u64> = host.drain().iter()
+            .filter(|x| x["type"] == "event_player_avatar_change")
+            .map(|x| x["color"].as_u64().unwrap())
+            .collect();
+        assert!(colors.len() <= 2, "{:?}", colors);
 
-            let event = OutEvent::EventRoomStart {
-                connection_type: msg.conn_type,
-                broadcast_id: format!("{}", room_id)
-            };
+        // The delayed ones follow in the next windows.
+        while colors.len() < 4 {
+            colors.push(host.recv_type("event_player_avatar_change").await["color"].as_u64().unwrap());
+        }
+        colors.sort();
+        assert_eq!(colors, vec![1, 2, 3, 4]);
+    }
 
-            let room = if room.in_game_count > 0 {
-                // Kick players that are still in-game
-                let mut in_game_players = vec![];
-                for id in room.players.iter() {
-                    if let Some(x) = self.players.get_mut(&id) {
-                        if x.in_game {
-                            in_game_players.push(*id);
-                        }
-                    }
-                }
+    #[actix_rt::test]
+    async fn messages_are_timestamped() {
+        let (db, config) = start_server(Config {
+            message_timestamps: true,
+            ..two_players()
+        });
+        let mut host = TestClient::connect(&db, &config);
+        let invite_id = host.create_room("host").await;
+        let mut guest = TestClient::connect(&db, &config);
+        let mut last = 0;
+        for res in [guest.login("guest").await, guest.call("room_join", json!({ "inviteId": invite_id })).await] {
+            let ts = res["ts"].as_u64().expect("No timestamp");
+            assert!(ts >= last);
+            last = ts;
+        }
+        start_game(&mut [&mut host, &mut guest], "server_broadcast").await;
 
-                for id in in_game_players {
-                    self.leave_room_if_any(ctx, id);
-                }
+        // The relayed packets have their own field.
+        host.send_text(r#"{"board": 1}"#);
+        let packet = guest.recv().await;
+        assert!(packet["ts"].is_null());
+        assert!(packet["timestamp"].as_u64().unwrap() >= last);
+    }
 
-                match self.rooms.get_mut(&room_id) {
-                    None => return,
-                    Some(x) => x,
-                }
-            } else {
-                room
-            };
+    #[actix_rt::test]
+    async fn stale_hosts_leave_no_room_behind() {
+        let (db, _) = start_server(Config::default());
+        let handled = db.send(Inspect(|server: &mut ServerActor, ctx: &mut Context<ServerActor>| {
+            // The session has been removed while the messages were queued.
+            let created = server.handle(CreateRoom { id: 42 }, ctx);
+            let found = server.handle(FindRoom { id: 42 }, ctx);
+            matches!(created, CreateRoomResult::SessionNotFound)
+                && matches!(found, FindRoomResult::SessionNotFound)
+                && server.create_room(42, true).is_none()
+                && server.rooms.is_empty()
+                && server.pub_rooms.is_empty()
+                && server.pub_rooms_available.is_empty()
+        })).await.unwrap();
+        assert!(handled);
+        assert_eq!(db.send(GetStats).await.unwrap().rooms, 0);
+    }
 
-            for id in room.players.iter() {
-                if let Some(x) = self.players.get_mut(&id) {
-                    x.in_game = true;
-                    let _ = x.addr.do_send(Event(event.clone()));// TODO: remove clone
-                }
+    #[actix_rt::test]
+    async fn roster_diffs_rebuild_the_roster() {
+        let (db, config) = start_server(Config {
+            start_countdown_secs: 60,
+            ..two_players()
+        });
+        let mut host = TestClient::connect(&db, &config);
+        host.call("login", json!({ "details": { "username": "host", "rosterDiffs": true } })).await;
+        let res = host.call("room_create", json!({})).await;
+        let invite_id = res["inviteId"].as_str().unwrap().to_string();
+        let mut roster: Vec<Value> = res["players"].as_array().unwrap().clone();
+
+        let mut b = TestClient::connect(&db, &config);
+        join(&mut b, "b", &invite_id).await;
+        let mut c = TestClient::connect(&db, &config);
+        join(&mut c, "c", &invite_id).await;
+        b.call("change_avatar", json!({ "avatar": 3, "color": 3 })).await;
+        c.call("room_leave", json!({})).await;
+        let mut d = TestClient::connect(&db, &config);
+        let mut full = d.join_room("d", &invite_id).await["players"].as_array().unwrap().clone();
+
+        // b and c joined, b changed its avatar, c left and d joined.
+        for _ in 0..5 {
+            let diff = host.recv_type("event_roster_diff").await;
+            roster.extend(diff["added"].as_array().unwrap().iter().cloned());
+            roster.retain(|x| !diff["removed"].as_array().unwrap().contains(&x["id"]));
+            for changed in diff["changed"].as_array().unwrap() {
+                *roster.iter_mut().find(|x| x["id"] == changed["id"]).unwrap() = changed.clone();
             }
-            room.in_game_count = room.players.len() as u32;
         }
+        let by_id = |x: &Value| x["id"].as_str().unwrap().to_string();
+        roster.sort_by_key(by_id);
+        full.sort_by_key(by_id);
+        assert_eq!(roster, full);
     }
-}
 
-impl Handler<SendRelayMex> for ServerActor {
-    type Result = ();
+    #[actix_rt::test]
+    async fn players_rejoin_the_room_they_left() {
+        let (db, config) = start_server(Config {
+            max_players: 2,
+            start_countdown_secs: 60,
+            ..two_players()
+        });
+        let mut host = TestClient::connect(&db, &config);
+        let invite_id = host.create_room("host").await;
+        let mut guest = TestClient::connect(&db, &config);
+        join(&mut guest, "guest", &invite_id).await;
+
+        guest.call("room_leave", json!({})).await;
+        let res = guest.call("room_rejoin", json!({})).await;
+        assert_eq!(res["result"], "ok", "{}", res);
+        assert_eq!(res["players"].as_array().unwrap().len(), 2);
+
+        // Someone else took the seat.
+        guest.call("room_leave", json!({})).await;
+        let mut other = TestClient::connect(&db, &config);
+        join(&mut other, "other", &invite_id).await;
+        assert_eq!(guest.call("room_rejoin", json!({})).await["result"], "room_is_full");
+
+        // The game has started.
+        start_game(&mut [&mut host, &mut other], "server_broadcast").await;
+        assert_eq!(guest.call("room_rejoin", json!({})).await["result"], "already_playing");
+
+        // The room is gone.
+        let mut alone = TestClient::connect(&db, &config);
+        alone.create_room("alone").await;
+        alone.call("room_leave", json!({})).await;
+        assert_eq!(alone.call("room_rejoin", json!({})).await["result"], "room_not_found");
+    }
 
-    fn handle(&mut self, msg: SendRelayMex, _ctx: &mut Context<Self>) -> Self::Result {
-        // TODO: do not clone.
-        // it's better to create a queue with multiple indexes
-        // A B C D E
-        //^     ^   ^
-        //p1    p2  p4
-        //      p3
-        if msg.data.is_empty() {
-            return;
-        }
+    #[actix_rt::test]
+    async fn rooms_are_fixed_to_a_single_host() {
+        let (db, config) = start_server(Config {
+            start_countdown_secs: 60,
+            ..two_players()
+        });
+        let mut host = TestClient::connect(&db, &config);
+        let invite_id = host.create_room("host").await;
+        let mut guest = TestClient::connect(&db, &config);
+        join(&mut guest, "guest", &invite_id).await;
+        settle().await;
+        host.drain();
+
+        let set_hosts = |host: bool, guest: bool| Inspect(move |server: &mut ServerActor, _: &mut Context<ServerActor>| {
+            for player in server.players.values_mut() {
+                player.obj.is_host = if player.obj.username == "host" { host } else { guest };
+            }
+            let room_id = *server.rooms.keys().next().unwrap();
+            server.check_room_host(room_id);
+            server.players.values().filter(|x| x.obj.is_host).count() == 1
+        });
+        assert!(db.send(set_hosts(true, true)).await.unwrap());
+
+        // Without a host the earliest joiner gets it back.
+        assert!(db.send(set_hosts(false, false)).await.unwrap());
+        host.recv_type("event_host_assigned").await;
+        settle().await;
+        assert!(!guest.drain().iter().any(|x| x["type"] == "event_host_assigned"));
+    }
 
-        let player = self.players.get(&msg.sender_id).expect("Expected player");
-        let room = match player.room.and_then(|room| self.rooms.get(&room)) {
-            Some(x) => x,
-            None => return,
+    #[actix_rt::test]
+    async fn binary_relays_are_prefixed_by_the_sender() {
+        let (db, config) = start_server(two_players());
+        let mut host = TestClient::connect(&db, &config);
+        let invite_id = host.create_room("host").await;
+        let mut guest = TestClient::connect(&db, &config);
+        let guest_id = guest.login("guest").await["playerId"].clone();
+        guest.call("room_join", json!({ "inviteId": invite_id })).await;
+        start_game(&mut [&mut host, &mut guest], "server_broadcast").await;
+        host.drain();
+
+        guest.send_binary(&[1, 2, 3]);
+        let data = loop {
+            match host.recv_frame().await {
+                Frame::Binary(x) => break x,
+                Frame::Ping(_) | Frame::Text(_) => continue,
+                x => panic!("Unexpected frame {:?}", x),
+            }
         };
+        let (header, payload) = data.split_at(mem::size_of::<u64>());
+        let mut sender = [0u8; 8];
+        sender.copy_from_slice(header);
+        assert_eq!(SerId(u64::from_be_bytes(sender) as IdType).to_string(), guest_id);
+        assert_eq!(payload, [1, 2, 3]);
+    }
 
-        let raw = format!("{{\"sender\":\"{}\",{}", SerId(msg.sender_id), &msg.data[1..]);
-        let raw_pkt = SendRelayMexRaw { data: raw };
-        for player in room.players.iter() {
-            if *player == msg.sender_id {
-                continue;
-            }
-            let player = match self.players.get(&player) {
-                Some(x) => x,
-                None => continue,
-            };
-            if player.in_game {
-                player.addr.do_send(raw_pkt.clone())
-            }
+    #[actix_rt::test]
+    async fn empty_public_rooms_are_kept_warm_up_to_the_pool_size() {
+        let (db, config) = start_server(Config {
+            max_players: 2,
+            start_countdown_secs: 60,
+            warm_room_pool: 1,
+            ..two_players()
+        });
+        let mut clients = vec![];
+        for name in &["first", "second", "third"] {
+            let mut client = TestClient::connect(&db, &config);
+            client.login(name).await;
+            let res = client.call("room_find", json!({})).await;
+            assert_eq!(res["result"], "ok", "{}", res);
+            clients.push(client);
         }
+        let rooms = |all: usize, warm: usize| Inspect(move |server: &mut ServerActor, _: &mut Context<ServerActor>| {
+            server.rooms.len() == all && server.warm_rooms.len() == warm
+        });
+        assert!(db.send(rooms(2, 0)).await.unwrap());
+
+        // The full room empties first and is kept, the other one exceeds the pool.
+        for client in clients.iter_mut() {
+            client.call("room_leave", json!({})).await;
+        }
+        settle().await;
+        assert!(db.send(rooms(1, 1)).await.unwrap());
+
+        let res = clients[2].call("room_find", json!({})).await;
+        assert_eq!(res["result"], "ok", "{}", res);
+        assert!(db.send(rooms(1, 0)).await.unwrap());
     }
-}
 
-impl Handler<GameEndRequest> for ServerActor {
-    type Result = Option<GameEndAck>;
+    #[actix_rt::test]
+    async fn available_rooms_are_indexed_by_free_slots() {
+        let (db, config) = start_server(Config {
+            max_players: 3,
+            start_countdown_secs: 60,
+            ..two_players()
+        });
+        // Checks the index against the rooms, then that a room with the given free slots is found.
+        let check = |free_slots: Option<usize>| Inspect(move |server: &mut ServerActor, _: &mut Context<ServerActor>| {
+            let consistent = server.pub_rooms_available.iter().all(|(room_id, free)| {
+                *free == server.config.max_players - server.rooms[room_id].players.len()
+                    && server.available_by_free_slots[*free].contains(room_id)
+            });
+            let indexed = server.available_by_free_slots.iter().map(HashSet::len).sum::<usize>();
+            let found = free_slots.and_then(|x| server.find_available_room(x..=x));
+            consistent && indexed == server.pub_rooms_available.len()
+                && found == free_slots.map(|_| *server.rooms.keys().next().unwrap())
+        });
+
+        let mut clients = vec![];
+        for (name, free_slots) in &[("first", Some(2)), ("second", Some(1)), ("third", None)] {
+            let mut client = TestClient::connect(&db, &config);
+            client.login(name).await;
+            client.call("room_find", json!({})).await;
+            assert!(db.send(check(*free_slots)).await.unwrap());
+            clients.push(client);
+        }
 
-    fn handle(&mut self, msg: GameEndRequest, _ctx: &mut Context<Self>) -> Self::Result {
-        let player = self.players.get_mut(&msg.id).expect("Invalid player");
-        let rooms = &mut self.rooms;
-        let room = match player.room.and_then(|x| rooms.get_mut(&x)) {
-            Some(x) => x,
-            None => return None,
+        clients[2].call("room_leave", json!({})).await;
+        assert!(db.send(check(Some(1))).await.unwrap());
+        clients[1].call("room_leave", json!({})).await;
+        assert!(db.send(check(Some(2))).await.unwrap());
+        clients[0].call("room_leave", json!({})).await;
+        assert!(db.send(check(None)).await.unwrap());
+    }
+
+    #[actix_rt::test]
+    async fn floods_are_shed_past_the_mailbox_capacity() {
+        let (db, _) = start_server(Config {
+            server_mailbox_capacity: 64,
+            ..two_players()
+        });
+        // The capacity is set once the actor has started.
+        db.send(IsShuttingDown).await.unwrap();
+        // Nothing is processed until the test yields, so the mailbox fills up.
+        let accepted = (0..1_000)
+            .filter(|_| match db.try_send(IsShuttingDown) {
+                Ok(()) => true,
+                Err(SendError::Full(_)) => false,
+                Err(SendError::Closed(_)) => panic!("Server stopped"),
+            })
+            .count();
+        assert!((64..=65).contains(&accepted), "{}", accepted);
+
+        // The server keeps up once the flood is over.
+        assert!(!db.send(IsShuttingDown).await.unwrap());
+    }
+
+    #[actix_rt::test]
+    async fn removed_rooms_dont_start() {
+        let (db, config) = start_server(Config {
+            start_countdown_secs: 1,
+            ..two_players()
+        });
+        let mut clients = vec![];
+        let mut room_id = Value::Null;
+        for name in &["first", "second"] {
+            let mut client = TestClient::connect(&db, &config);
+            client.login(name).await;
+            room_id = client.call("room_find", json!({})).await["roomId"].clone();
+            clients.push(client);
+        }
+        let room_id = InviteId::parse(room_id.as_str().unwrap()).unwrap().id;
+        let counting = db.send(Inspect(move |server: &mut ServerActor, _: &mut Context<ServerActor>| {
+            server.rooms[&room_id].start_countdown_handle.is_some()
+        })).await.unwrap();
+        assert!(counting);
+        for client in clients.iter_mut() {
+            client.call("room_leave", json!({})).await;
+        }
+
+        let removed = db.send(Inspect(move |server: &mut ServerActor, ctx: &mut Context<ServerActor>| {
+            let removed = server.rooms.is_empty();
+            // Even if it was still scheduled, the start would find no room.
+            server.handle(CountdownEnded { room_id }, ctx);
+            removed && server.rooms.is_empty() && server.playing_room_count() == 0
+        })).await.unwrap();
+        assert!(removed);
+        actix_rt::time::delay_for(Duration::from_millis(1_100)).await;
+        for client in clients.iter_mut() {
+            assert!(!client.drain().iter().any(|x| x["type"] == "event_room_start"));
+        }
+    }
+
+    #[actix_rt::test]
+    async fn admins_see_how_long_players_have_been_idle() {
+        let (db, config) = start_server(Config {
+            heartbeat_interval_ms: 50,
+            ..two_players()
+        });
+        let mut client = TestClient::connect(&db, &config);
+        client.login("idle").await;
+        let idle_secs = || async {
+            db.send(ListPlayers { room: None, page: 0 }).await.unwrap().players[0].idle_secs
         };
 
-        if !player.in_game {
-            return None;
+        actix_rt::time::delay_for(Duration::from_millis(1_100)).await;
+        assert_eq!(idle_secs().await, 1);
+
+        // Reported with the next heartbeat.
+        client.call("capabilities", json!({})).await;
+        actix_rt::time::delay_for(Duration::from_millis(100)).await;
+        assert_eq!(idle_secs().await, 0);
+    }
+
+    #[actix_rt::test]
+    async fn private_rooms_open_when_they_dont_fill() {
+        let (db, config) = start_server(Config {
+            start_countdown_secs: 60,
+            private_room_auto_open_ms: 50,
+            ..two_players()
+        });
+        let mut host = TestClient::connect(&db, &config);
+        host.create_room("host").await;
+        let mut stranger = TestClient::connect(&db, &config);
+        stranger.login("stranger").await;
+        let res = stranger.call("room_find", json!({})).await;
+        assert_eq!(res["justCreated"], true);
+        stranger.call("room_leave", json!({})).await;
+
+        let event = host.recv_type("event_room_visibility_changed").await;
+        assert_eq!(event["public"], true);
+        let res = stranger.call("room_find", json!({})).await;
+        assert_eq!(res["justCreated"], false);
+        assert_eq!(res["players"].as_array().unwrap().len(), 2);
+    }
+
+    #[actix_rt::test]
+    async fn the_earliest_joiner_becomes_the_host() {
+        let (db, config) = start_server(Config {
+            start_countdown_secs: 60,
+            ..two_players()
+        });
+        let mut host = TestClient::connect(&db, &config);
+        let invite_id = host.create_room("host").await;
+        let mut second = TestClient::connect(&db, &config);
+        let second_id = second.login("second").await["playerId"].clone();
+        second.call("room_join", json!({ "inviteId": invite_id })).await;
+        let mut third = TestClient::connect(&db, &config);
+        join(&mut third, "third", &invite_id).await;
+
+        host.call("room_leave", json!({})).await;
+        second.recv_type("event_host_assigned").await;
+        let left = third.recv_type("event_player_left").await;
+        assert_eq!(left["newHost"], second_id);
+        settle().await;
+        assert!(!third.drain().iter().any(|x| x["type"] == "event_host_assigned"));
+
+        // The countdown goes on with the new host.
+        let counting = db.send(Inspect(|server: &mut ServerActor, _: &mut Context<ServerActor>| {
+            server.rooms.values().all(|x| x.start_countdown_handle.is_some())
+        })).await.unwrap();
+        assert!(counting);
+    }
+
+    #[actix_rt::test]
+    async fn dropped_players_reconnect_within_the_grace() {
+        let (db, config) = start_server(Config {
+            reconnect_grace_ms: 100,
+            ..two_players()
+        });
+        let mut host = TestClient::connect(&db, &config);
+        let invite_id = host.create_room("host").await;
+        let mut guest = TestClient::connect(&db, &config);
+        let login = guest.login("guest").await;
+        guest.call("room_join", json!({ "inviteId": invite_id })).await;
+        start_game(&mut [&mut host, &mut guest], "server_broadcast").await;
+        let players = db.send(ListPlayers { room: None, page: 0 }).await.unwrap().players;
+        let reconnect = json!({
+            "playerId": login["playerId"],
+            "roomId": players[0].room.unwrap().to_string(),
+            "token": login["reconnectToken"],
+        });
+
+        guest.disconnect();
+        host.recv_type("player_disconnected").await;
+        let mut guest = TestClient::connect(&db, &config);
+        let res = guest.call("reconnect", reconnect.clone()).await;
+        assert_eq!(res["result"], "ok", "{}", res);
+        host.recv_type("player_reconnected").await;
+        // Back to playing, its packets are relayed again.
+        guest.send_text(&json!({ "n": 1 }).to_string());
+        assert_eq!(host.recv().await["n"], 1);
+
+        // Past the grace the seat is gone.
+        guest.disconnect();
+        let left = host.recv_type("player_left").await;
+        assert_eq!(left["player"], login["playerId"]);
+        let mut guest = TestClient::connect(&db, &config);
+        let res = guest.call("reconnect", reconnect).await;
+        assert_eq!(res["result"], "session_not_found");
+    }
+
+    #[actix_rt::test]
+    async fn lobby_chat_reaches_the_other_players() {
+        let (db, config) = start_server(two_players());
+        let mut host = TestClient::connect(&db, &config);
+        let host_id = host.login("host").await["playerId"].clone();
+        let invite_id = host.call("room_create", json!({})).await["inviteId"].as_str().unwrap().to_string();
+        let mut guest = TestClient::connect(&db, &config);
+        join(&mut guest, "guest", &invite_id).await;
+
+        for (text, error) in [("  ", "empty_chat_message"), ("a\u{7}", "invalid_chat_message")] {
+            let res = host.call("chat_send", json!({ "text": text })).await;
+            assert_eq!(res["error"], error);
         }
+        let res = host.call("chat_send", json!({ "text": "a".repeat(257) })).await;
+        assert_eq!(res["error"], "chat_message_too_long");
+
+        host.request("chat_send", json!({ "text": " gg " }));
+        let event = guest.recv_type("event_chat").await;
+        assert_eq!(event["player"], host_id);
+        assert_eq!(event["text"], "gg");
+        settle().await;
+        assert!(!host.drain().iter().any(|x| x["type"] == "event_chat"));
+    }
 
-        room.state = RoomState::Matchmaking;
-        player.in_game = false;
-        room.in_game_count -= 1;
+    #[actix_rt::test]
+    async fn peer_to_peer_games_have_no_turns() {
+        let (db, config) = start_server(two_players());
+        let mut host = TestClient::connect(&db, &config);
+        let invite_id = host.create_room("host").await;
+        let mut guest = TestClient::connect(&db, &config);
+        join(&mut guest, "guest", &invite_id).await;
+        start_game(&mut [&mut host, &mut guest], "peer_to_peer").await;
+
+        host.send_special(json!({ "type": "end_turn" }));
+        assert_eq!(host.recv_type("error").await["error"], "relay_disabled");
+        host.send_special(json!({ "type": "pause" }));
+        assert_eq!(host.recv_type("error").await["error"], "relay_disabled");
+
+        // A burst of packets only gets one error.
+        for _ in 0..5 {
+            host.send_text(r#"{"board": 1}"#);
+        }
+        settle().await;
+        let errors: Vec<Value> = host.drain().into_iter().filter(|x| x["type"] == "error").collect();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0]["error"], "relay_disabled");
+        assert!(guest.drain().iter().all(|x| x["type"] != "game_paused" && x["board"].is_null()));
+    }
 
-        let room = self.rooms.get(&player.room.unwrap()).unwrap();
+    #[actix_rt::test]
+    async fn private_relays_skip_the_spectators() {
+        let (db, config) = start_server(two_players());
+        let mut host = TestClient::connect(&db, &config);
+        let invite_id = host.create_room("host").await;
+        let mut guest = TestClient::connect(&db, &config);
+        join(&mut guest, "guest", &invite_id).await;
+        let start = start_game(&mut [&mut host, &mut guest], "server_broadcast").await;
+        let mut spectator = TestClient::connect(&db, &config);
+        spectator.login("spectator").await;
+        spectator.call("spectate_join", json!({ "roomId": start["broadcastId"] })).await;
+
+        host.send_text(r#"{"hand": 1, "private": true}"#);
+        host.send_text(r#"{"board": 2}"#);
+        assert_eq!(guest.recv().await["hand"], 1);
+        assert_eq!(guest.recv().await["board"], 2);
+        assert_eq!(spectator.recv().await["board"], 2);
+        settle().await;
+        assert!(spectator.drain().is_empty());
+    }
 
-        let users = room.players.iter()
-            .map(|x| self.players.get(x).expect("Cannot find player").obj.clone())
-            .collect();
+    #[actix_rt::test]
+    async fn late_joiners_get_the_chat_history() {
+        let (db, config) = start_server(Config::default());
+        let mut host = TestClient::connect(&db, &config);
+        let invite_id = host.create_room("host").await;
+        for i in 0..CHAT_HISTORY_SIZE + 5 {
+            host.request("chat_send", json!({ "text": format!("message {}", i) }));
+        }
+        settle().await;
+
+        let mut guest = TestClient::connect(&db, &config);
+        join(&mut guest, "guest", &invite_id).await;
+        let history = guest.recv().await;
+        assert_eq!(history["type"], "event_chat_history");
+        let messages = history["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), CHAT_HISTORY_SIZE);
+        assert_eq!(messages[0]["text"], "message 5");
+        assert_eq!(messages[CHAT_HISTORY_SIZE - 1]["text"], format!("message {}", CHAT_HISTORY_SIZE + 4));
+
+        // Nothing to send to the first player of a room.
+        let mut other = TestClient::connect(&db, &config);
+        other.create_room("other").await;
+        settle().await;
+        assert!(other.drain().is_empty());
+    }
 
-        return Some(GameEndAck(users));
+    #[actix_rt::test]
+    async fn roster_changes_reset_the_ready_players() {
+        let (db, config) = start_server(Config::default());
+        let mut host = TestClient::connect(&db, &config);
+        let invite_id = host.create_room("host").await;
+        let mut guest = TestClient::connect(&db, &config);
+        guest.join_room("guest", &invite_id).await;
+
+        host.request("set_ready", json!({ "ready": true }));
+        guest.request("set_ready", json!({ "ready": true }));
+        settle().await;
+        host.drain();
+
+        let mut third = TestClient::connect(&db, &config);
+        third.join_room("third", &invite_id).await;
+        let events: Vec<_> = host.drain().into_iter().filter(|x| x["type"] == "event_player_ready").collect();
+        assert_eq!(events.len(), 2);
+        assert!(events.iter().all(|x| x["ready"] == false));
+
+        third.request("set_ready", json!({ "ready": true }));
+        settle().await;
+        host.drain();
+        guest.call("room_leave", json!({})).await;
+        settle().await;
+        let events = host.drain();
+        assert_eq!(events[0]["type"], "event_player_left");
+        assert_eq!(events[1]["type"], "event_player_ready");
+        assert_eq!(events[1]["ready"], false);
+        assert_eq!(events.len(), 2);
     }
 }
-
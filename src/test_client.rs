@@ -0,0 +1,316 @@
+//! In-memory websocket client used by the tests to drive a ClientWs without any socket.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::future::poll_fn;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+use actix::prelude::*;
+use actix_web::error::PayloadError;
+use actix_web::web::Bytes;
+use actix_web_actors::ws;
+use serde_json::{json, Value};
+
+use crate::client_ws::ClientWs;
+use crate::config::Config;
+use crate::server_actor::ServerActor;
+
+/// How long recv waits for the server before failing the test.
+const RECV_TIMEOUT: Duration = Duration::from_secs(2);
+
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Frame {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Close(Option<u16>),
+}
+
+/// Frames sent by the client, read by the websocket context.
+#[derive(Default)]
+struct Pipe {
+    frames: VecDeque<Bytes>,
+    closed: bool,
+    waker: Option<Waker>,
+}
+
+struct PipeStream(Rc<RefCell<Pipe>>);
+
+impl Stream for PipeStream {
+    type Item = Result<Bytes, PayloadError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut pipe = self.0.borrow_mut();
+        if let Some(x) = pipe.frames.pop_front() {
+            return Poll::Ready(Some(Ok(x)));
+        }
+        if pipe.closed {
+            return Poll::Ready(None);
+        }
+        pipe.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+#[derive(Default)]
+struct Received {
+    buf: Vec<u8>,
+    frames: VecDeque<Frame>,
+    ended: bool,
+}
+
+impl Received {
+    /// Decodes the complete (unmasked) frames written by the server.
+    fn decode(&mut self) {
+        loop {
+            if self.buf.len() < 2 {
+                return;
+            }
+            let opcode = self.buf[0] & 0x0F;
+            let (len, header) = match self.buf[1] & 0x7F {
+                126 if self.buf.len() >= 4 => (u16::from_be_bytes([self.buf[2], self.buf[3]]) as usize, 4),
+                127 if self.buf.len() >= 10 => {
+                    let mut len = [0u8; 8];
+                    len.copy_from_slice(&self.buf[2..10]);
+                    (u64::from_be_bytes(len) as usize, 10)
+                },
+                126 | 127 => return,
+                x => (x as usize, 2),
+            };
+            if self.buf.len() < header + len {
+                return;
+            }
+            let payload: Vec<u8> = self.buf.drain(..header + len).skip(header).collect();
+            let frame = match opcode {
+                OP_TEXT => Frame::Text(String::from_utf8(payload).expect("Invalid text frame")),
+                OP_BINARY => Frame::Binary(payload),
+                OP_CLOSE => Frame::Close(if payload.len() >= 2 { Some(u16::from_be_bytes([payload[0], payload[1]])) } else { None }),
+                OP_PING => Frame::Ping(payload),
+                _ => continue,
+            };
+            self.frames.push_back(frame);
+        }
+    }
+}
+
+/// A client connected to its own ClientWs actor, the frames are exchanged in memory.
+pub struct TestClient {
+    input: Rc<RefCell<Pipe>>,
+    output: Rc<RefCell<Received>>,
+    next_id: u64,
+}
+
+impl TestClient {
+    pub fn connect(db: &Addr<ServerActor>, config: &Arc<Config>) -> Self {
+        TestClient::start(ClientWs::new(db.clone(), config.clone()))
+    }
+
+    pub fn start(actor: ClientWs) -> Self {
+        let input = Rc::new(RefCell::new(Pipe::default()));
+        let output = Rc::new(RefCell::new(Received::default()));
+
+        let mut stream = Box::pin(ws::WebsocketContext::create(actor, PipeStream(input.clone())));
+        let received = output.clone();
+        actix_rt::spawn(async move {
+            while let Some(Ok(data)) = poll_fn(|cx| stream.as_mut().poll_next(cx)).await {
+                let mut received = received.borrow_mut();
+                received.buf.extend_from_slice(&data);
+                received.decode();
+            }
+            received.borrow_mut().ended = true;
+        });
+
+        TestClient {
+            input,
+            output,
+            next_id: 0,
+        }
+    }
+
+    /// Sends a masked frame (with a zero mask, so the payload stays readable).
+    pub fn send_frame(&self, opcode: u8, payload: &[u8]) {
+        let mut data = vec![0x80 | opcode];
+        match payload.len() {
+            x if x < 126 => data.push(0x80 | x as u8),
+            x if x <= u16::MAX as usize => {
+                data.push(0x80 | 126);
+                data.extend_from_slice(&(x as u16).to_be_bytes());
+            },
+            x => {
+                data.push(0x80 | 127);
+                data.extend_from_slice(&(x as u64).to_be_bytes());
+            },
+        }
+        data.extend_from_slice(&[0; 4]);
+        data.extend_from_slice(payload);
+
+        let mut pipe = self.input.borrow_mut();
+        pipe.frames.push_back(Bytes::from(data));
+        if let Some(x) = pipe.waker.take() {
+            x.wake();
+        }
+    }
+
+    pub fn send_text(&self, text: &str) {
+        self.send_frame(OP_TEXT, text.as_bytes());
+    }
+
+    pub fn send_binary(&self, data: &[u8]) {
+        self.send_frame(OP_BINARY, data);
+    }
+
+    pub fn send_pong(&self, data: &[u8]) {
+        self.send_frame(OP_PONG, data);
+    }
+
+    /// Sends a message of the given type with the fields of `data`, returns its id.
+    pub fn request(&mut self, mtype: &str, data: Value) -> u64 {
+        self.next_id += 1;
+        let mut mex = json!({ "id": self.next_id, "type": mtype });
+        if let Value::Object(fields) = data {
+            mex.as_object_mut().unwrap().extend(fields);
+        }
+        self.send_text(&mex.to_string());
+        self.next_id
+    }
+
+    /// Sends a special message (only used in game).
+    pub fn send_special(&self, mex: Value) {
+        self.send_text(&format!("#{}", mex));
+    }
+
+    /// Closes the connection, as if the socket was dropped.
+    pub fn disconnect(&self) {
+        let mut pipe = self.input.borrow_mut();
+        pipe.closed = true;
+        if let Some(x) = pipe.waker.take() {
+            x.wake();
+        }
+    }
+
+    /// The server has closed the connection (and every frame has been read).
+    pub fn is_closed(&self) -> bool {
+        let output = self.output.borrow();
+        output.ended && output.frames.is_empty()
+    }
+
+    pub async fn recv_frame(&mut self) -> Frame {
+        let start = Instant::now();
+        loop {
+            if let Some(x) = self.output.borrow_mut().frames.pop_front() {
+                return x;
+            }
+            assert!(!self.output.borrow().ended, "Connection closed");
+            assert!(start.elapsed() < RECV_TIMEOUT, "No message received");
+            actix_rt::time::delay_for(Duration::from_millis(1)).await;
+        }
+    }
+
+    /// Receives the next message, skipping the pings. The special messages are returned without their '#'.
+    pub async fn recv(&mut self) -> Value {
+        loop {
+            match self.recv_frame().await {
+                Frame::Text(text) => return parse_message(&text),
+                Frame::Ping(_) => continue,
+                x => panic!("Unexpected frame {:?}", x),
+            }
+        }
+    }
+
+    /// Skips the messages until the one of the given type.
+    pub async fn recv_type(&mut self, mtype: &str) -> Value {
+        loop {
+            let mex = self.recv().await;
+            if mex["type"] == mtype {
+                return mex;
+            }
+        }
+    }
+
+    /// Skips the messages until the answer to the request (a response or an error).
+    pub async fn reply(&mut self, request_id: u64) -> Value {
+        loop {
+            let mex = self.recv().await;
+            if mex["requestId"] == request_id || mex["originId"] == request_id {
+                return mex;
+            }
+        }
+    }
+
+    /// Sends a request and waits for its answer.
+    pub async fn call(&mut self, mtype: &str, data: Value) -> Value {
+        let id = self.request(mtype, data);
+        self.reply(id).await
+    }
+
+    /// Every message received so far (without waiting).
+    pub fn drain(&mut self) -> Vec<Value> {
+        let mut output = self.output.borrow_mut();
+        output.frames.drain(..)
+            .filter_map(|x| match x {
+                Frame::Text(text) => Some(parse_message(&text)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Waits for the start of the game and acknowledges it, returns the event_room_start.
+    pub async fn ack_start(&mut self) -> Value {
+        let event = self.recv_type("event_room_start").await;
+        self.request("event_room_start_ack", json!({ "requestId": event["id"] }));
+        event
+    }
+
+    /// Logs in and returns the login response.
+    pub async fn login(&mut self, username: &str) -> Value {
+        let res = self.call("login", json!({ "details": { "username": username } })).await;
+        assert_eq!(res["result"], "ok", "{}", res);
+        res
+    }
+
+    /// Logs in and creates a private room, returns its invite id.
+    pub async fn create_room(&mut self, username: &str) -> String {
+        self.login(username).await;
+        let res = self.call("room_create", json!({})).await;
+        assert_eq!(res["result"], "ok", "{}", res);
+        res["inviteId"].as_str().unwrap().to_string()
+    }
+
+    /// Logs in and joins the room.
+    pub async fn join_room(&mut self, username: &str, invite_id: &str) -> Value {
+        self.login(username).await;
+        let res = self.call("room_join", json!({ "inviteId": invite_id })).await;
+        assert_eq!(res["result"], "ok", "{}", res);
+        res
+    }
+}
+
+/// The host (the first client) starts the game and everyone acknowledges it, returns the event_room_start.
+pub async fn start_game(clients: &mut [&mut TestClient], connection_type: &str) -> Value {
+    clients[0].request("room_start", json!({ "connectionType": connection_type }));
+    let mut event = Value::Null;
+    for client in clients.iter_mut() {
+        event = client.ack_start().await;
+    }
+    settle().await;
+    event
+}
+
+fn parse_message(text: &str) -> Value {
+    serde_json::from_str(text.strip_prefix('#').unwrap_or(text)).expect("Invalid json")
+}
+
+/// Gives the actors the time to exchange their messages.
+pub async fn settle() {
+    actix_rt::time::delay_for(Duration::from_millis(20)).await;
+}